@@ -1,56 +1,34 @@
-extern crate tokio_proto;
+extern crate futures;
+extern crate tokio_core;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
-#[macro_use]
-extern crate macro_attr;
 
-use varlink::server::{Proto, VarlinkService};
-use tokio_proto::TcpServer;
+use varlink::middleware::Latency;
+use varlink::observer::{Level, Logger};
+use varlink::server::{self, VarlinkService};
+use futures::{Future, Stream};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
 use std::sync::{Arc, RwLock};
 
 
 extern crate varlink;
 
 #[macro_use]
-mod io_systemd_network {
-    /*
-    varlink_server! (r#"
-    # Provides information about network state
-	interface io.systemd.network
-
-	type NetdevInfo (
-	  ifindex: int,
-	  ifname: string
-	)
-
-	type Netdev (
-	  ifindex: int,
-	  ifname: string
-	)
-
-	# Returns information about a network device
-	method Info(ifindex: int) -> (info: NetdevInfo)
-
-	# Lists all network devices
-	method List() -> (netdevs: Netdev[])
-
-	error UnknownNetworkDevice
-	error InvalidParameter
-    "#);
-    */
-
-    // will be autogenerated by the above macro in the future
-    include!("io_systemd_network/mod.rs");
+extern crate varlink_derive;
 
+// The client is not used here.
+#[allow(dead_code)]
+mod io_systemd_network {
+    varlink_server_file!("examples/io_systemd_network/io.systemd.network.varlink");
 }
 
 use io_systemd_network::*;
 
-macro_attr! { #[derive(IoSystemdNetwork!)]
 struct MyServer {
     pub state: Arc<RwLock<i64>>,
-}}
+}
 
 impl Interface for MyServer {
     fn info(&self, i: i64) -> Result<InfoReply, Error> {
@@ -110,20 +88,31 @@ fn main() {
     // Specify the localhost address
     let addr = "0.0.0.0:12345".parse().unwrap();
 
-    // The builder requires a protocol and an address
-    let server = TcpServer::new(Proto, addr);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
 
     let state = Arc::new(RwLock::new(0));
-
-    // We provide a way to *instantiate* the service for each new
-    // connection; here, we just immediately return a new instance.
-    server.serve(move || {
-        println!("New Server");
-        let iosystemdnetwork = MyServer { state: state.clone() };
-        Ok(VarlinkService::new("org.varlink".into(),
-                               "test service".into(),
-                               "0.1".into(),
-                               "http://varlink.org".into(),
-                               vec![Box::new(iosystemdnetwork)]))
-    });
+    let logger = Arc::new(Logger::new(Level::Debug));
+
+    // Instantiate the service for each new connection and serve the
+    // connection on the event loop.
+    let server = listener
+        .incoming()
+        .for_each(|(sock, _)| {
+            println!("New Server");
+            let iosystemdnetwork = io_systemd_network::new(MyServer { state: state.clone() });
+            let mut service = VarlinkService::new("org.varlink".into(),
+                                                  "test service".into(),
+                                                  "0.1".into(),
+                                                  "http://varlink.org".into(),
+                                                  vec![iosystemdnetwork]);
+            service.set_parameter_validation(true);
+            service.set_observer(logger.clone());
+            service.add_middleware(Latency::new(|method, t| eprintln!("{} took {:?}", method, t)));
+            handle.spawn(server::serve(sock, service).map_err(|e| println!("{}", e)));
+            Ok(())
+        });
+
+    core.run(server).unwrap();
 }
@@ -0,0 +1,756 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use std::io;
+use std::marker::PhantomData;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+
+pub use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+
+use codec::ReplyCodec;
+use server::{self, Request, Response, Upgraded};
+
+/// A client connection to a varlink service.
+pub struct Connection<T> {
+    transport: Framed<T, ReplyCodec>,
+}
+
+impl<T> Connection<T>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    pub fn new(io: T) -> Self {
+        Connection { transport: io.framed(ReplyCodec::default()) }
+    }
+
+    /// Sends `req` and resolves to its reply and the connection for further
+    /// calls.
+    pub fn call(self, req: Request) -> Box<Future<Item = (Response, Self), Error = io::Error>> {
+        Box::new(Replies::new(self, req)
+                     .into_future()
+                     .map_err(|(e, _)| e)
+                     .and_then(|(resp, replies)| match resp {
+                                   Some(resp) => Ok((resp, replies.into_connection())),
+                                   None => Err(unexpected_eof()),
+                               }))
+    }
+
+    /// Sends `req` with `oneway` set, the service does not reply to it.
+    /// Resolves to the connection once the request is written.
+    pub fn call_oneway(self, mut req: Request) -> Box<Future<Item = Self, Error = io::Error>> {
+        req.oneway = Some(true);
+        Box::new(self.transport
+                     .send(req)
+                     .map(|transport| Connection { transport: transport }))
+    }
+
+    /// Sends `req` with `upgrade` set and resolves to its reply and the
+    /// underlying connection, which after a successful reply speaks the
+    /// protocol the call upgraded to.
+    pub fn call_upgrade(self,
+                        mut req: Request)
+                        -> Box<Future<Item = (Response, Upgraded<T>), Error = io::Error>> {
+        req.upgrade = Some(true);
+        Box::new(self.call(req)
+                     .map(|(resp, conn)| (resp, Upgraded::from(conn.transport.into_parts()))))
+    }
+
+    /// Sends `req` with `more` set and returns the stream of all its replies.
+    pub fn call_more(self, mut req: Request) -> Replies<T> {
+        req.more = Some(true);
+        Replies::new(self, req)
+    }
+
+    /// Like `call`, but the reply is deserialized into `R` and an error
+    /// converted into `E`, for the generated clients.
+    pub fn call_typed<R, E>(self, req: Request) -> TypedCall<T, R, E>
+        where R: DeserializeOwned,
+              E: From<server::Error>
+    {
+        TypedCall { replies: Some(TypedReplies::new(Replies::new(self, req))) }
+    }
+
+    /// Like `call_more`, but with the replies deserialized into `R` and errors
+    /// converted into `E`.
+    pub fn call_more_typed<R, E>(self, req: Request) -> TypedReplies<T, R, E>
+        where R: DeserializeOwned,
+              E: From<server::Error>
+    {
+        TypedReplies::new(self.call_more(req))
+    }
+
+    /// Like `call_typed`, with the parameters of `req` serialized from
+    /// `args`. If they can't be, nothing is sent and the call fails with
+    /// `InvalidInput`.
+    pub fn call_typed_with<A, R, E>(self, req: Request, args: &A) -> TypedCall<T, R, E>
+        where A: Serialize,
+              R: DeserializeOwned,
+              E: From<server::Error>
+    {
+        TypedCall { replies: Some(TypedReplies::new(Replies::with_args(self, req, args))) }
+    }
+
+    /// Like `call_more_typed`, with the parameters serialized from `args`, see
+    /// `call_typed_with`.
+    pub fn call_more_typed_with<A, R, E>(self, mut req: Request, args: &A) -> TypedReplies<T, R, E>
+        where A: Serialize,
+              R: DeserializeOwned,
+              E: From<server::Error>
+    {
+        req.more = Some(true);
+        TypedReplies::new(Replies::with_args(self, req, args))
+    }
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by service")
+}
+
+/// The replies to a single request, ending after the first reply without
+/// `continues`.
+pub struct Replies<T> {
+    transport: Framed<T, ReplyCodec>,
+    request: Option<Request>,
+    done: bool,
+    /// Fails the stream before anything is sent.
+    error: Option<io::Error>,
+}
+
+impl<T> Replies<T>
+    where T: AsyncRead + AsyncWrite
+{
+    fn new(conn: Connection<T>, req: Request) -> Self {
+        Replies {
+            transport: conn.transport,
+            request: Some(req),
+            done: false,
+            error: None,
+        }
+    }
+
+    fn with_args<A: Serialize>(conn: Connection<T>, mut req: Request, args: &A) -> Self {
+        match serde_json::to_value(args) {
+            Ok(parameters) => {
+                req.parameters = Some(parameters);
+                Replies::new(conn, req)
+            }
+            Err(e) => {
+                Replies {
+                    transport: conn.transport,
+                    request: None,
+                    done: true,
+                    error: Some(io::Error::new(io::ErrorKind::InvalidInput,
+                                               format!("invalid parameters: {}", e))),
+                }
+            }
+        }
+    }
+
+    /// Returns the connection, once all replies have been received it can be
+    /// used for further calls.
+    pub fn into_connection(self) -> Connection<T> {
+        Connection { transport: self.transport }
+    }
+}
+
+impl<T> Stream for Replies<T>
+    where T: AsyncRead + AsyncWrite
+{
+    type Item = Response;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Response>, io::Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if let Some(req) = self.request.take() {
+            if let AsyncSink::NotReady(req) = self.transport.start_send(req)? {
+                self.request = Some(req);
+                return Ok(Async::NotReady);
+            }
+        }
+        try_ready!(self.transport.poll_complete());
+
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+
+        match try_ready!(self.transport.poll()) {
+            Some(resp) => {
+                self.done = !resp.continues();
+                Ok(Async::Ready(Some(resp)))
+            }
+            None => Err(unexpected_eof()),
+        }
+    }
+}
+
+/// A reply which doesn't match the method's output is an error of the
+/// service, not of the call.
+fn invalid_reply(e: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid reply: {}", e))
+}
+
+/// The replies to a single request as `Result<R, E>`, see
+/// `Connection::call_more_typed`.
+pub struct TypedReplies<T, R, E> {
+    replies: Replies<T>,
+    marker: PhantomData<(R, E)>,
+}
+
+impl<T, R, E> TypedReplies<T, R, E>
+    where T: AsyncRead + AsyncWrite
+{
+    fn new(replies: Replies<T>) -> Self {
+        TypedReplies {
+            replies: replies,
+            marker: PhantomData,
+        }
+    }
+
+    /// See `Replies::into_connection`.
+    pub fn into_connection(self) -> Connection<T> {
+        self.replies.into_connection()
+    }
+}
+
+impl<T, R, E> Stream for TypedReplies<T, R, E>
+    where T: AsyncRead + AsyncWrite,
+          R: DeserializeOwned,
+          E: From<server::Error>
+{
+    type Item = Result<R, E>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let reply = match try_ready!(self.replies.poll()) {
+            Some(Response::Ok(reply)) |
+            Some(Response::Upgrade(reply, _)) => reply,
+            Some(Response::Err(e)) => return Ok(Async::Ready(Some(Err(E::from(e))))),
+            None => return Ok(Async::Ready(None)),
+        };
+        let parameters = reply
+            .parameters
+            .unwrap_or_else(|| Value::Object(serde_json::Map::new()));
+        let reply = serde_json::from_value(parameters).map_err(invalid_reply)?;
+        Ok(Async::Ready(Some(Ok(reply))))
+    }
+}
+
+/// The reply to a single request as `Result<R, E>` and the connection, see
+/// `Connection::call_typed`.
+pub struct TypedCall<T, R, E> {
+    replies: Option<TypedReplies<T, R, E>>,
+}
+
+impl<T, R, E> Future for TypedCall<T, R, E>
+    where T: AsyncRead + AsyncWrite,
+          R: DeserializeOwned,
+          E: From<server::Error>
+{
+    type Item = (Result<R, E>, Connection<T>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        let reply = {
+            let replies = self.replies
+                .as_mut()
+                .expect("TypedCall polled after completion");
+            match try_ready!(replies.poll()) {
+                Some(reply) => reply,
+                None => return Err(unexpected_eof()),
+            }
+        };
+        let conn = self.replies.take().unwrap().into_connection();
+        Ok(Async::Ready((reply, conn)))
+    }
+}
+
+#[cfg(test)]
+use server::{AsyncInterface, Error, Interface, RawTransport, Reply, ReplyStream, Upgrade,
+             ValueFuture, VarlinkService};
+
+#[cfg(test)]
+struct Counter;
+
+#[cfg(test)]
+impl Interface for Counter {
+    fn get_description(&self) -> &'static str {
+        "interface org.example.counter
+method Count(n: int) -> (i: int)
+method Truncated(n: int) -> (i: int)
+method Echo() -> ()"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.counter"
+    }
+
+    fn call(&self, _: Request) -> Result<Value, Error> {
+        Ok(json!({"i": 0}))
+    }
+
+    fn call_more(&self, req: Request) -> Result<ReplyStream, Error> {
+        // `Truncated` forgets the final reply.
+        let truncated = req.method == "org.example.counter.Truncated";
+        let n = req.parameters.unwrap()["n"].as_i64().unwrap();
+        let replies = (0..n).map(move |i| if truncated || i + 1 < n {
+                                     Ok(Reply::continues(json!({"i": i})))
+                                 } else {
+                                     Ok(Reply::new(json!({"i": i})))
+                                 });
+        Ok(Box::new(::futures::stream::iter_result(replies)))
+    }
+
+    fn call_upgrade(&self, _: Request) -> Result<(Value, Option<Box<Upgrade>>), Error> {
+        // Echoes four bytes of the upgraded protocol.
+        let echo = |io: Box<RawTransport>| -> Box<Future<Item = (), Error = io::Error>> {
+            Box::new(::tokio_io::io::read_exact(io, [0u8; 4])
+                         .and_then(|(io, buf)| ::tokio_io::io::write_all(io, buf))
+                         .map(|_| ()))
+        };
+        Ok((json!({}), Some(Box::new(echo))))
+    }
+}
+
+#[cfg(test)]
+struct Delayed(::tokio_core::reactor::Handle);
+
+#[cfg(test)]
+impl AsyncInterface for Delayed {
+    fn get_description(&self) -> &'static str {
+        "interface org.example.delayed\nmethod Wait(ms: int) -> ()"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.delayed"
+    }
+
+    fn call(&self, req: Request) -> ValueFuture {
+        use std::time::Duration;
+        use tokio_core::reactor::Timeout;
+
+        let ms = req.parameters.unwrap()["ms"].as_u64().unwrap();
+        let timeout = Timeout::new(Duration::from_millis(ms), &self.0).unwrap();
+        Box::new(timeout
+                     .map(move |_| json!({"ms": ms}))
+                     .map_err(|_| Error::default()))
+    }
+}
+
+/// `WaitForPing` blocks until `Ping` is called, possibly on another
+/// connection.
+#[cfg(test)]
+struct Sleeper {
+    pinged: ::std::sync::Mutex<::std::sync::mpsc::Sender<()>>,
+    ping: ::std::sync::Mutex<::std::sync::mpsc::Receiver<()>>,
+}
+
+#[cfg(test)]
+impl Sleeper {
+    fn new() -> Self {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        Sleeper {
+            pinged: ::std::sync::Mutex::new(tx),
+            ping: ::std::sync::Mutex::new(rx),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Interface for Sleeper {
+    fn get_description(&self) -> &'static str {
+        "
+interface org.example.sleeper
+method WaitForPing() -> (pinged: bool)
+method Ping() -> ()
+method Panic() -> ()
+"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.sleeper"
+    }
+
+    fn call(&self, req: Request) -> Result<Value, Error> {
+        use std::time::Duration;
+
+        match req.method.as_ref() {
+            "org.example.sleeper.WaitForPing" => {
+                // Only times out if the ping can't be handled meanwhile.
+                let ping = self.ping.lock().unwrap();
+                Ok(json!({"pinged": ping.recv_timeout(Duration::from_secs(10)).is_ok()}))
+            }
+            "org.example.sleeper.Panic" => panic!("no sleep"),
+            _ => {
+                self.pinged.lock().unwrap().send(()).unwrap();
+                Ok(json!({}))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+struct Whoami;
+
+#[cfg(test)]
+impl Interface for Whoami {
+    fn get_description(&self) -> &'static str {
+        "interface org.example.whoami\nmethod Whoami() -> (uid: int, pid: int, calls: int, id: int)"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.whoami"
+    }
+
+    fn call(&self, req: Request) -> Result<Value, Error> {
+        let ctx = req.context;
+        let cred = *ctx.peer_credentials().unwrap();
+        let calls = ctx.get("calls").map_or(0, |v| v.as_i64().unwrap()) + 1;
+        ctx.set("calls", json!(calls));
+        Ok(json!({"uid": cred.uid, "pid": cred.pid, "calls": calls, "id": ctx.connection_id()}))
+    }
+}
+
+#[cfg(test)]
+fn serve_test_service(handle: &::tokio_core::reactor::Handle) -> ::std::net::SocketAddr {
+    use tokio_core::net::TcpListener;
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let h = handle.clone();
+    let pool = server::CpuPool::new(2);
+    let sleeper = ::std::sync::Arc::new(Sleeper::new());
+    let server = listener
+        .incoming()
+        .for_each(move |(sock, _)| {
+            let mut service = VarlinkService::new("org.varlink".into(),
+                                                  "test".into(),
+                                                  "0.1".into(),
+                                                  "http://varlink.org".into(),
+                                                  vec![Box::new(Counter)]);
+            service.add_async_interface(Box::new(Delayed(h.clone())));
+            service.add_blocking_methods(sleeper.clone(), pool.clone(), &["WaitForPing", "Panic"]);
+            h.spawn(server::serve(sock, service).map_err(|e| panic!("{}", e)));
+            Ok(())
+        });
+    handle.spawn(server.map_err(|e| panic!("{}", e)));
+    addr
+}
+
+#[test]
+fn test_call_more() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    let request = Request {
+        method: "org.example.counter.Count".into(),
+        parameters: Some(json!({"n": 3})),
+        ..Default::default()
+    };
+
+    let client = TcpStream::connect(&addr, &handle)
+        .and_then(|sock| Connection::new(sock).call_more(request).collect());
+    let replies = core.run(client).unwrap();
+
+    let ids: Vec<(i64, bool)> = replies
+        .iter()
+        .map(|r| match *r {
+                 Response::Ok(ref reply) => {
+                     (reply.parameters.as_ref().unwrap()["i"].as_i64().unwrap(), r.continues())
+                 }
+                 _ => panic!("unexpected error reply"),
+             })
+        .collect();
+    assert_eq!(ids, vec![(0, true), (1, true), (2, false)]);
+}
+
+#[test]
+fn test_call_more_truncated() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    let request = Request {
+        method: "org.example.counter.Truncated".into(),
+        parameters: Some(json!({"n": 2})),
+        ..Default::default()
+    };
+
+    let client = TcpStream::connect(&addr, &handle)
+        .and_then(|sock| Connection::new(sock).call_more(request).collect());
+    let replies = core.run(client).unwrap();
+
+    assert_eq!(replies.len(), 3);
+    assert!(replies[1].continues());
+    match replies[2] {
+        Response::Err(ref e) => assert_eq!(e.error, "org.varlink.service.InternalError"),
+        _ => panic!("expected an error reply"),
+    }
+}
+
+#[test]
+fn test_call_oneway() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    let oneway = Request {
+        method: "org.example.counter.Count".into(),
+        parameters: Some(json!({"n": 3})),
+        more: Some(true),
+        ..Default::default()
+    };
+    let info = Request {
+        method: "org.varlink.service.GetInfo".into(),
+        ..Default::default()
+    };
+
+    let client = TcpStream::connect(&addr, &handle).and_then(|sock| {
+        Connection::new(sock)
+            .call_oneway(oneway)
+            .and_then(|conn| conn.call(info))
+    });
+    let (resp, _) = core.run(client).unwrap();
+
+    match resp {
+        Response::Ok(reply) => assert_eq!(reply.parameters.unwrap()["product"], "test"),
+        _ => panic!("unexpected error reply"),
+    }
+}
+
+#[test]
+fn test_call_upgrade() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+    use tokio_io::io::{read_exact, write_all};
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    // The data of the upgraded protocol directly follows the request.
+    let msg = b"{\"method\":\"org.example.counter.Echo\",\"parameters\":null,\"upgrade\":true}\0ping";
+    let expected = b"{\"parameters\":{}}\0ping";
+
+    let client = TcpStream::connect(&addr, &handle)
+        .and_then(|sock| write_all(sock, &msg[..]))
+        .and_then(|(sock, _)| read_exact(sock, [0u8; 22]));
+    let (_, buf) = core.run(client).unwrap();
+    assert_eq!(&buf[..], &expected[..]);
+
+    let addr = serve_test_service(&handle);
+    let request = Request {
+        method: "org.example.counter.Echo".into(),
+        ..Default::default()
+    };
+    let client = TcpStream::connect(&addr, &handle)
+        .and_then(|sock| Connection::new(sock).call_upgrade(request))
+        .and_then(|(_, io)| write_all(io, b"pong"))
+        .and_then(|(io, _)| read_exact(io, [0u8; 4]));
+    let (_, buf) = core.run(client).unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[test]
+fn test_call_async() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    let wait = |ms| {
+        Request {
+            method: "org.example.delayed.Wait".into(),
+            parameters: Some(json!({"ms": ms})),
+            ..Default::default()
+        }
+    };
+
+    let client = TcpStream::connect(&addr, &handle).and_then(|sock| {
+        Connection::new(sock)
+            .call(wait(20))
+            .and_then(move |(first, conn)| conn.call(wait(0)).map(|(second, _)| (first, second)))
+    });
+    let (first, second) = core.run(client).unwrap();
+
+    for (resp, ms) in [(first, 20), (second, 0)] {
+        match resp {
+            Response::Ok(reply) => assert_eq!(reply.parameters.unwrap()["ms"], ms),
+            _ => panic!("unexpected error reply"),
+        }
+    }
+}
+
+#[test]
+fn test_call_blocking() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    let call = |method: &str| {
+        let req = Request {
+            method: method.to_string().into(),
+            ..Default::default()
+        };
+        TcpStream::connect(&addr, &handle)
+            .and_then(|sock| Connection::new(sock).call(req))
+            .map(|(resp, _)| resp)
+    };
+    let wait = call("org.example.sleeper.WaitForPing");
+    let ping = call("org.example.sleeper.Ping");
+    let panic = call("org.example.sleeper.Panic");
+
+    let ((waited, _), panicked) = core.run(wait.join(ping).join(panic)).unwrap();
+    match waited {
+        Response::Ok(reply) => assert_eq!(reply.parameters.unwrap()["pinged"], true),
+        _ => panic!("unexpected error reply"),
+    }
+    match panicked {
+        Response::Err(e) => assert_eq!(e.error, "org.varlink.service.InternalError"),
+        _ => panic!("expected an error reply"),
+    }
+}
+
+#[test]
+fn test_call_context() {
+    use tokio_core::reactor::Core;
+    use tokio_uds::UnixStream;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (server_io, client_io) = UnixStream::pair(&handle).unwrap();
+
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![Box::new(Whoami)]);
+    handle.spawn(server::serve_with_peer(server_io, service).map_err(|e| panic!("{}", e)));
+
+    let whoami = || {
+        Request {
+            method: "org.example.whoami.Whoami".into(),
+            ..Default::default()
+        }
+    };
+    let client = Connection::new(client_io)
+        .call(whoami())
+        .and_then(move |(first, conn)| conn.call(whoami()).map(|(second, _)| (first, second)));
+    let (first, second) = core.run(client).unwrap();
+
+    let (first, second) = match (first, second) {
+        (Response::Ok(first), Response::Ok(second)) => {
+            (first.parameters.unwrap(), second.parameters.unwrap())
+        }
+        _ => panic!("unexpected error reply"),
+    };
+    assert_eq!(first["uid"], unsafe { ::libc::getuid() });
+    assert_eq!(first["pid"], unsafe { ::libc::getpid() });
+    assert_eq!(first["calls"], 1);
+    assert_eq!(second["calls"], 2);
+    assert_eq!(first["id"], second["id"]);
+    assert!(first["id"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn test_malformed_request() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+    use tokio_io::io::write_all;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    let info = || {
+        Request {
+            method: "org.varlink.service.GetInfo".into(),
+            ..Default::default()
+        }
+    };
+
+    // The first reply is the error for the malformed message, the connection
+    // stays usable.
+    let client = TcpStream::connect(&addr, &handle)
+        .and_then(|sock| write_all(sock, &b"{\"method\":\0"[..]))
+        .and_then(move |(sock, _)| Connection::new(sock).call(info()))
+        .and_then(move |(error, conn)| conn.call(info()).map(|(reply, _)| (error, reply)));
+    let (error, reply) = core.run(client).unwrap();
+
+    match error {
+        Response::Err(e) => assert_eq!(e.error, "org.varlink.service.InvalidParameter"),
+        _ => panic!("expected an error reply"),
+    }
+    match reply {
+        Response::Ok(reply) => assert_eq!(reply.parameters.unwrap()["product"], "test"),
+        _ => panic!("unexpected error reply"),
+    }
+}
+
+#[cfg(test)]
+struct Unserializable;
+
+#[cfg(test)]
+impl Serialize for Unserializable {
+    fn serialize<S: ::serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+
+        Err(S::Error::custom("unserializable"))
+    }
+}
+
+#[test]
+fn test_call_typed_with_invalid_args() {
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = serve_test_service(&handle);
+
+    let request = || {
+        Request {
+            method: "org.example.counter.Count".into(),
+            ..Default::default()
+        }
+    };
+    let client = TcpStream::connect(&addr, &handle).and_then(|sock| {
+        Connection::new(sock).call_typed_with::<_, Value, server::Error>(request(), &Unserializable)
+    });
+    match core.run(client) {
+        Err(e) => {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(e.to_string(), "invalid parameters: unserializable");
+        }
+        Ok(_) => panic!("expected an error"),
+    }
+
+    let client = TcpStream::connect(&addr, &handle).and_then(|sock| {
+        Connection::new(sock)
+            .call_more_typed_with::<_, Value, server::Error>(request(), &Unserializable)
+            .collect()
+    });
+    match core.run(client) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        Ok(_) => panic!("expected an error"),
+    }
+}
@@ -0,0 +1,385 @@
+//! The framing of varlink messages: JSON objects, each terminated by a NUL
+//! byte.
+//!
+//! `NulJsonCodec` is the service side of a connection, it decodes requests
+//! and encodes responses. `ReplyCodec` is the client side, it encodes requests
+//! and decodes responses. Both share the framing, including the limit of the
+//! message size.
+
+use serde_json::{self, Map, Value};
+
+use std::cmp;
+use std::io;
+
+use bytes::{BufMut, BytesMut};
+
+use tokio_io::codec::{Decoder, Encoder};
+
+use server::{self, Error, Reply, Request, Response};
+
+/// The default maximum size of a message, see `NulJsonCodec::new`.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Splits the messages off a buffer.
+struct Framing {
+    max_size: usize,
+    /// How far the buffer was already searched for the NUL byte.
+    scanned: usize,
+}
+
+impl Framing {
+    fn new(max_size: usize) -> Self {
+        Framing {
+            max_size: max_size,
+            scanned: 0,
+        }
+    }
+
+    /// Removes the next message and its NUL from `buf`.
+    fn next_frame(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let end = cmp::min(buf.len(), self.max_size + 1);
+        let i = match buf[self.scanned..end].iter().position(|&b| b == 0) {
+            Some(i) => self.scanned + i,
+            None => {
+                if end > self.max_size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("varlink message larger than {} bytes",
+                                                      self.max_size)));
+                }
+                self.scanned = end;
+                return Ok(None);
+            }
+        };
+        self.scanned = 0;
+
+        let mut frame = buf.split_to(i + 1);
+        frame.truncate(i);
+        Ok(Some(frame))
+    }
+}
+
+fn encode_frame<T: ::serde::Serialize>(msg: &T, buf: &mut BytesMut) -> io::Result<()> {
+    let json = serde_json::to_vec(msg)?;
+    buf.reserve(json.len() + 1);
+    buf.put_slice(&json);
+    buf.put_u8(0);
+    Ok(())
+}
+
+/// Frames the messages of the service side of a connection.
+///
+/// Frames which are no valid request are decoded to an error, which is sent
+/// as the reply to it. A message larger than the maximum size is an error of
+/// the transport and ends the connection.
+pub struct NulJsonCodec(Framing);
+
+impl NulJsonCodec {
+    pub fn new(max_size: usize) -> Self {
+        NulJsonCodec(Framing::new(max_size))
+    }
+}
+
+impl Default for NulJsonCodec {
+    fn default() -> Self {
+        NulJsonCodec::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+impl Decoder for NulJsonCodec {
+    type Item = Result<Request, Error>;
+    type Error = io::Error;
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        match self.0.next_frame(buf)? {
+            Some(frame) => Ok(Some(decode_request(&frame))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A message which is no valid request is an `InvalidParameter` error,
+/// naming the invalid field if the message is an object.
+fn decode_request(frame: &[u8]) -> Result<Request, Error> {
+    let mut msg: Map<String, Value> = serde_json::from_slice(frame)
+        .map_err(Error::invalid_parameters)?;
+    Ok(Request {
+           method: server::parameter(&mut msg, "method")?,
+           parameters: server::parameter(&mut msg, "parameters")?,
+           more: server::parameter(&mut msg, "more")?,
+           oneway: server::parameter(&mut msg, "oneway")?,
+           upgrade: server::parameter(&mut msg, "upgrade")?,
+           context: Default::default(),
+       })
+}
+
+impl Encoder for NulJsonCodec {
+    type Item = Response;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> io::Result<()> {
+        let reply = match msg {
+            Response::Ok(reply) |
+            Response::Upgrade(reply, _) => reply,
+            Response::Err(e) => Reply::from(e),
+        };
+        encode_frame(&reply, buf)
+    }
+}
+
+/// Frames the messages of the client side of a connection.
+///
+/// Replies with an `error` are decoded to `Response::Err`, all others to
+/// `Response::Ok`. A reply which can't be decoded is an error of the
+/// transport.
+pub struct ReplyCodec(Framing);
+
+impl ReplyCodec {
+    pub fn new(max_size: usize) -> Self {
+        ReplyCodec(Framing::new(max_size))
+    }
+}
+
+impl Default for ReplyCodec {
+    fn default() -> Self {
+        ReplyCodec::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+impl Decoder for ReplyCodec {
+    type Item = Response;
+    type Error = io::Error;
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Response>> {
+        let frame = match self.0.next_frame(buf)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let reply: Reply = serde_json::from_slice(&frame)?;
+        Ok(Some(Response::from(reply)))
+    }
+}
+
+impl Encoder for ReplyCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Request, buf: &mut BytesMut) -> io::Result<()> {
+        encode_frame(&msg, buf)
+    }
+}
+
+#[cfg(test)]
+fn decode_all(codec: &mut NulJsonCodec, buf: &mut BytesMut) -> Vec<Result<Request, Error>> {
+    let mut items = Vec::new();
+    while let Some(item) = codec.decode(buf).unwrap() {
+        items.push(item);
+    }
+    items
+}
+
+#[test]
+fn test_codec_malformed() {
+    let mut codec = NulJsonCodec::default();
+    let mut buf = BytesMut::from(&b"{\"method\"\0[1,2]\0{\"method\":\"org.example.a.B\"}\0{"[..]);
+    let items = decode_all(&mut codec, &mut buf);
+    assert_eq!(items.len(), 3);
+    for item in &items[..2] {
+        match *item {
+            Err(ref e) => assert_eq!(e.error, "org.varlink.service.InvalidParameter"),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+    match items[2] {
+        Ok(ref req) => assert_eq!(req.method, "org.example.a.B"),
+        Err(_) => panic!("expected a request"),
+    }
+    assert_eq!(&buf[..], b"{");
+}
+
+#[test]
+fn test_codec_max_size() {
+    let msg = b"{\"method\":\"org.example.a.B\"}\0";
+    let mut codec = NulJsonCodec::new(msg.len() - 1);
+    let mut buf = BytesMut::from(&msg[..]);
+    assert_eq!(decode_all(&mut codec, &mut buf).len(), 1);
+
+    let mut codec = NulJsonCodec::new(msg.len() - 2);
+    let mut buf = BytesMut::from(&msg[..]);
+    assert!(codec.decode(&mut buf).is_err());
+
+    // Without a NUL, the limit is hit as soon as it is exceeded.
+    let mut codec = NulJsonCodec::new(16);
+    let mut buf = BytesMut::from(&[b' '; 16][..]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(b" ");
+    assert!(codec.decode(&mut buf).is_err());
+}
+
+#[test]
+fn test_codec_fuzz() {
+    // xorshift, so failures can be reproduced
+    let mut state: u32 = 0x2545_f491;
+    let mut random = move |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as usize % n
+    };
+
+    for _ in 0..200 {
+        // Frames of random bytes, some valid requests, in random chunks.
+        let mut stream = Vec::new();
+        let mut valid = Vec::new();
+        for _ in 0..random(8) {
+            if random(2) == 0 {
+                stream.extend_from_slice(b"{\"method\":\"org.example.a.B\"}");
+                valid.push(true);
+            } else {
+                for _ in 0..random(64) {
+                    stream.push(cmp::max(random(256) as u8, 1));
+                }
+                valid.push(false);
+            }
+            stream.push(0);
+        }
+        for _ in 0..random(16) {
+            stream.push(cmp::max(random(256) as u8, 1));
+        }
+
+        let mut codec = NulJsonCodec::new(64);
+        let mut buf = BytesMut::new();
+        let mut items = Vec::new();
+        let mut rest = &stream[..];
+        while !rest.is_empty() {
+            let (chunk, r) = rest.split_at(cmp::min(1 + random(32), rest.len()));
+            rest = r;
+            buf.extend_from_slice(chunk);
+            items.extend(decode_all(&mut codec, &mut buf));
+        }
+
+        assert_eq!(items.len(), valid.len());
+        for (item, &valid) in items.iter().zip(&valid) {
+            // Random bytes are almost never a request.
+            assert_eq!(item.is_ok(), valid);
+        }
+    }
+}
+
+#[test]
+fn test_reply_codec() {
+    let req = Request {
+        method: "org.example.a.B".into(),
+        parameters: Some(json!({"n": 1})),
+        more: Some(true),
+        ..Default::default()
+    };
+    let mut buf = BytesMut::new();
+    ReplyCodec::default().encode(req, &mut buf).unwrap();
+    match decode_all(&mut NulJsonCodec::default(), &mut buf).pop() {
+        Some(Ok(req)) => {
+            assert_eq!(req.method, "org.example.a.B");
+            assert_eq!(req.parameters, Some(json!({"n": 1})));
+            assert_eq!(req.more, Some(true));
+        }
+        _ => panic!("expected a request"),
+    }
+
+    let mut codec = NulJsonCodec::default();
+    codec
+        .encode(Response::Ok(Reply::continues(json!({"i": 0}))), &mut buf)
+        .unwrap();
+    codec
+        .encode(Response::Ok(Reply::new(json!({"i": 1}))), &mut buf)
+        .unwrap();
+    codec
+        .encode(Response::Err(Error::method_not_found("org.example.a.C")), &mut buf)
+        .unwrap();
+
+    let mut codec = ReplyCodec::default();
+    let mut responses = Vec::new();
+    while let Some(resp) = codec.decode(&mut buf).unwrap() {
+        responses.push(resp);
+    }
+    assert_eq!(responses.len(), 3);
+    match responses[0] {
+        Response::Ok(ref reply) => {
+            assert_eq!(reply.parameters, Some(json!({"i": 0})));
+            assert_eq!(reply.continues, Some(true));
+        }
+        _ => panic!("expected a reply"),
+    }
+    match responses[1] {
+        Response::Ok(ref reply) => assert_eq!(reply.continues, None),
+        _ => panic!("expected a reply"),
+    }
+    match responses[2] {
+        Response::Err(ref e) => {
+            assert_eq!(e.error, "org.varlink.service.MethodNotFound");
+            assert_eq!(e.parameters, Some(json!({"method": "C"})));
+        }
+        _ => panic!("expected an error"),
+    }
+
+    let mut buf = BytesMut::from(&b"{\"parameters\":\0"[..]);
+    assert!(codec.decode(&mut buf).is_err());
+}
+
+#[cfg(test)]
+fn encode_all<C: Encoder>(codec: &mut C, msgs: Vec<C::Item>) -> BytesMut
+    where C::Error: ::std::fmt::Debug
+{
+    let mut buf = BytesMut::new();
+    for msg in msgs {
+        codec.encode(msg, &mut buf).unwrap();
+    }
+    buf
+}
+
+#[test]
+fn test_golden() {
+    let mut codec = NulJsonCodec::default();
+    let reply = encode_all(&mut codec,
+                           vec![Response::Ok(Reply::new(json!({
+                                     "description": "interface org.example.a"
+                                 })))]);
+    assert_eq!(&reply[..], &include_bytes!("../tests/golden/reply.bin")[..]);
+
+    let error = encode_all(&mut codec,
+                           vec![Response::Err(Error::method_not_found("org.example.a.C")),
+                                Response::Err(Error::permission_denied())]);
+    assert_eq!(&error[..], &include_bytes!("../tests/golden/error.bin")[..]);
+
+    let more = encode_all(&mut codec,
+                          vec![Response::Ok(Reply::continues(json!({"i": 0}))),
+                               Response::Ok(Reply::continues(json!({"i": 1}))),
+                               Response::Ok(Reply::new(json!({"i": 2})))]);
+    assert_eq!(&more[..], &include_bytes!("../tests/golden/more.bin")[..]);
+
+    let request = encode_all(&mut ReplyCodec::default(),
+                             vec![Request {
+                                      method: "org.example.a.Count".into(),
+                                      parameters: Some(json!({"n": 3})),
+                                      more: Some(true),
+                                      ..Default::default()
+                                  },
+                                  Request {
+                                      method: "org.example.a.Ping".into(),
+                                      oneway: Some(true),
+                                      ..Default::default()
+                                  }]);
+    assert_eq!(&request[..], &include_bytes!("../tests/golden/request.bin")[..]);
+}
+
+#[test]
+fn test_error_message() {
+    let e = decode_request(b"{\"more\":true}").err().unwrap();
+    assert_eq!(e.error, "org.varlink.service.InvalidParameter");
+    assert_eq!(e.parameters, Some(json!({"parameter": "method"})));
+    assert!(e.message.is_some());
+
+    let e = decode_request(b"{\"method\":\"org.example.a.Ping\",\"more\":\"field `x`\"}")
+        .err()
+        .unwrap();
+    assert_eq!(e.parameters, Some(json!({"parameter": "more"})));
+
+    let e = decode_request(b"[]").err().unwrap();
+    assert_eq!(e.parameters, Some(json!({"parameter": "parameters"})));
+}
@@ -0,0 +1,901 @@
+//! Generates Rust code from varlink interface descriptions.
+//!
+//! The code is meant to be generated at build time. In `build.rs`:
+//!
+//! ```rust,ignore
+//! extern crate varlink;
+//!
+//! fn main() {
+//!     varlink::generator::cargo_build("src/org.example.ping.varlink");
+//! }
+//! ```
+//!
+//! and in the crate, where `serde_json`, `serde_derive` and `varlink` are
+//! available:
+//!
+//! ```rust,ignore
+//! mod org_example_ping {
+//!     include!(concat!(env!("OUT_DIR"), "/org_example_ping.rs"));
+//! }
+//! ```
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use parser::*;
+
+/// The definitions of anonymous structs and enums, in the order they are
+/// found.
+type Nested = Vec<String>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The interface description does not parse.
+    Parse(String),
+    /// Two definitions of the interface get the same name in the Rust code.
+    Collision(String),
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "an I/O error occurred",
+            Error::Parse(_) => "the interface description is invalid",
+            Error::Collision(_) => "the interface description has conflicting names",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::Io(ref err) => Some(&*err as &StdError),
+            Error::Parse(_) | Error::Collision(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::Parse(ref err) => write!(f, "{}", err),
+            Error::Collision(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+trait ToRust {
+    /// The Rust type. Anonymous structs and enums are named `parent` and
+    /// their definitions added to `nested`.
+    fn to_rust(&self, parent: &str, nested: &mut Nested) -> Result<String, Error>;
+}
+
+impl<'a> ToRust for VType<'a> {
+    fn to_rust(&self, parent: &str, nested: &mut Nested) -> Result<String, Error> {
+        match *self {
+            VType::Bool(_) => Ok("bool".into()),
+            VType::Int(_) => Ok("i64".into()),
+            VType::Float(_) => Ok("f64".into()),
+            VType::VString(_) => Ok("String".into()),
+            VType::VData(_) => Ok("String".into()),
+            VType::VTypename(v) => Ok(v.into()),
+            VType::VEnum(ref v) => {
+                nested.push(enum_to_rust(parent, &[], v));
+                Ok(parent.into())
+            }
+            VType::VStruct(ref v) => {
+                let def = struct_to_rust(parent, &[], v, nested)?;
+                nested.push(def);
+                Ok(parent.into())
+            }
+        }
+    }
+}
+
+impl<'a> ToRust for VTypeExt<'a> {
+    fn to_rust(&self, parent: &str, nested: &mut Nested) -> Result<String, Error> {
+        let mut v = self.vtype.to_rust(parent, nested)?;
+
+        if self.isarray {
+            v = format!("Vec<{}>", v);
+        }
+        if self.isoption {
+            v = format!("Option<{}>", v);
+        }
+        Ok(v)
+    }
+}
+
+impl<'a> ToRust for Interface<'a> {
+    fn to_rust(&self, _: &str, _: &mut Nested) -> Result<String, Error> {
+        let mut out: String = "".to_owned();
+
+        for t in self.typedefs.values() {
+            match t.elt {
+                VStructOrEnum::VStruct(ref v) => {
+                    out += struct_with_nested(t.name, &t.doc, v)?.as_ref()
+                }
+                VStructOrEnum::VEnum(ref v) => out += enum_to_rust(t.name, &t.doc, v).as_ref(),
+            }
+        }
+
+        for t in self.methods.values() {
+            // Also for methods without output, `()` is no JSON object.
+            out += struct_with_nested(&format!("{}Reply", t.name), &[], &t.output)?.as_ref();
+            if !t.input.elts.is_empty() {
+                out += struct_with_nested(&format!("{}Args", t.name), &[], &t.input)?.as_ref();
+            }
+        }
+
+        out += "#[derive(Debug)]\n";
+        out += "pub enum Error {\n";
+        for t in self.errors.values() {
+            out += doc_to_rust(&t.doc, "    ///").as_ref();
+            if t.parm.elts.is_empty() {
+                out += format!("    {},\n", t.name).as_ref();
+            } else {
+                out += format!("    {}({}Error),\n", t.name, t.name).as_ref();
+            }
+        }
+        out += "    /// Any other error, e.g. of `org.varlink.service`.\n";
+        out += "    Varlink(varlink::server::Error),\n";
+        out += "}\n\n";
+
+        for t in self.errors.values() {
+            if t.parm.elts.is_empty() {
+                continue;
+            }
+            out += struct_with_nested(&format!("{}Error", t.name), &[], &t.parm)?.as_ref();
+        }
+
+        out += "impl From<Error> for varlink::server::Error {\n";
+        out += "    fn from(e: Error) -> Self {\n";
+        out += "        match e {\n";
+        for t in self.errors.values() {
+            if t.parm.elts.is_empty() {
+                out += format!("            Error::{} => {{\n", t.name).as_ref();
+                out += format!("                varlink::server::Error::new(\"{}.{}\", None)\n",
+                               self.name,
+                               t.name)
+                    .as_ref();
+            } else {
+                out += format!("            Error::{}(p) => {{\n", t.name).as_ref();
+                out += "                match serde_json::to_value(p) {\n";
+                out += format!("                    Ok(p) => varlink::server::Error::new(\"{}.{}\", Some(p)),\n",
+                               self.name,
+                               t.name)
+                    .as_ref();
+                out += "                    Err(e) => varlink::server::Error::internal_error(e.to_string()),\n";
+                out += "                }\n";
+            }
+            out += "            }\n";
+        }
+        out += "            Error::Varlink(e) => e,\n";
+        out += "        }\n";
+        out += "    }\n";
+        out += "}\n\n";
+
+        out += "/// Errors of this interface with parameters which don't match are kept\n";
+        out += "/// as `Error::Varlink`.\n";
+        out += "impl From<varlink::server::Error> for Error {\n";
+        out += "    fn from(e: varlink::server::Error) -> Self {\n";
+        for t in self.errors.values() {
+            out += format!("        if e.error == \"{}.{}\" {{\n", self.name, t.name).as_ref();
+            if t.parm.elts.is_empty() {
+                out += format!("            return Error::{};\n", t.name).as_ref();
+            } else {
+                out += "            let parameters = e.parameters.clone().unwrap_or_default();\n";
+                out += "            if let Ok(p) = serde_json::from_value(parameters) {\n";
+                out += format!("                return Error::{}(p);\n", t.name).as_ref();
+                out += "            }\n";
+            }
+            out += "        }\n";
+        }
+        out += "        Error::Varlink(e)\n";
+        out += "    }\n";
+        out += "}\n\n";
+
+        out += "/// A failure of the service, not of the call.\n";
+        out += "impl From<serde_json::Error> for Error {\n";
+        out += "    fn from(e: serde_json::Error) -> Self {\n";
+        out += "        Error::Varlink(varlink::server::Error::internal_error(e.to_string()))\n";
+        out += "    }\n";
+        out += "}\n\n";
+
+        out += doc_to_rust(&self.doc, "///").as_ref();
+        out += "pub trait Interface {\n";
+        for t in self.methods.values() {
+            let mut inparms: String = "".to_owned();
+            for e in &t.input.elts {
+                inparms += format!(", {}: {}", field_name(e.name), arg_type(t.name, e)?).as_ref();
+            }
+
+            out += doc_to_rust(&t.doc, "    ///").as_ref();
+            out += format!("    fn {}(&self{}) -> Result<{}Reply, Error>;\n",
+                           fn_name(t.name),
+                           inparms,
+                           t.name)
+                .as_ref();
+        }
+        out += "}\n\n";
+
+        Ok(out)
+    }
+}
+
+/// A struct field, in snake_case and renamed to its name in the description
+/// for JSON. Nullable fields are left out of the JSON when `None`.
+fn field_to_rust(e: &Argument, parent: &str, nested: &mut Nested) -> Result<String, Error> {
+    let mut out = doc_to_rust(&e.doc, "    ///");
+    if e.vtype.isoption {
+        out += "    #[serde(skip_serializing_if = \"Option::is_none\", default)]\n";
+    }
+    let field = field_name(e.name);
+    if field.trim_start_matches("r#") != e.name {
+        out += format!("    #[serde(rename = \"{}\")]\n", e.name).as_ref();
+    }
+    out += format!("    pub {}: {},\n", field, e.vtype.to_rust(parent, nested)?).as_ref();
+    Ok(out)
+}
+
+/// The struct `name`. The anonymous types of its fields are named after the
+/// struct and the field, e.g. `InfoReplyAddress` for `address` of `InfoReply`.
+fn struct_to_rust(name: &str,
+                  doc: &[&str],
+                  v: &VStruct,
+                  nested: &mut Nested)
+                  -> Result<String, Error> {
+    let mut out = doc_to_rust(doc, "///");
+    out += "#[derive(Serialize, Deserialize, Debug)]\n";
+    out += format!("pub struct {} {{\n", name).as_ref();
+    for e in &v.elts {
+        out += field_to_rust(e, &format!("{}{}", name, camel_case(e.name)), nested)?.as_ref();
+    }
+    out += "}\n\n";
+    Ok(out)
+}
+
+/// The struct `name`, followed by the anonymous types used in it.
+fn struct_with_nested(name: &str, doc: &[&str], v: &VStruct) -> Result<String, Error> {
+    let mut nested = Nested::new();
+    let mut out = struct_to_rust(name, doc, v, &mut nested)?;
+    for def in nested {
+        out += def.as_ref();
+    }
+    Ok(out)
+}
+
+/// The enum `name`, with the values as CamelCase variants.
+fn enum_to_rust(name: &str, doc: &[&str], v: &VEnum) -> String {
+    let mut out = doc_to_rust(doc, "///");
+    out += "#[derive(Serialize, Deserialize, Debug)]\n";
+    out += format!("pub enum {} {{\n", name).as_ref();
+    for elt in &v.elts {
+        let variant = ident(&camel_case(elt));
+        if variant != *elt {
+            out += format!("    #[serde(rename = \"{}\")]\n", elt).as_ref();
+        }
+        out += format!("    {},\n", variant).as_ref();
+    }
+    out += "}\n\n";
+    out
+}
+
+/// The lines of `doc` as comments, starting with `prefix`, e.g. `    ///`.
+fn doc_to_rust(doc: &[&str], prefix: &str) -> String {
+    let mut out: String = "".to_owned();
+    for line in doc {
+        if line.is_empty() {
+            out += format!("{}\n", prefix).as_ref();
+        } else {
+            out += format!("{} {}\n", prefix, line).as_ref();
+        }
+    }
+    out
+}
+
+/// The type of the argument `e` of `method`, as in the `Args` struct.
+fn arg_type(method: &str, e: &Argument) -> Result<String, Error> {
+    e.vtype
+        .to_rust(&format!("{}Args{}", method, camel_case(e.name)), &mut Nested::new())
+}
+
+/// `foo_bar` as `FooBar`. Leading underscores are kept.
+fn camel_case(name: &str) -> String {
+    let rest = name.trim_start_matches('_');
+    let prefix = &name[..name.len() - rest.len()];
+    let camel: String = rest.split('_')
+        .map(|part| {
+                 let mut c = part.chars();
+                 match c.next() {
+                     None => String::new(),
+                     Some(f) => f.to_uppercase().chain(c).collect(),
+                 }
+             })
+        .collect();
+    prefix.to_owned() + &camel
+}
+
+/// `GetIPAddress` as `get_ip_address`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if i > 0 && c.is_uppercase() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).map(|n| n.is_lowercase()) == Some(true);
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_lower) {
+                out.push('_');
+            }
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+const KEYWORDS: &[&str] = &["abstract", "as", "async", "await", "become", "box", "break",
+                            "const", "continue", "do", "dyn", "else", "enum", "extern",
+                            "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+                            "loop", "macro", "match", "mod", "move", "mut", "override", "priv",
+                            "pub", "ref", "return", "static", "struct", "trait", "true", "try",
+                            "type", "typeof", "unsafe", "unsized", "use", "virtual", "where",
+                            "while", "yield"];
+
+/// `name` as a Rust identifier. Keywords become raw identifiers, except the
+/// ones which can't be raw, which get a `_` appended.
+fn ident(name: &str) -> String {
+    match name {
+        "_" | "crate" | "self" | "Self" | "super" => format!("{}_", name),
+        _ if KEYWORDS.contains(&name) => format!("r#{}", name),
+        _ => name.to_owned(),
+    }
+}
+
+/// The function for `method`, e.g. `get_info` for `GetInfo`.
+fn fn_name(method: &str) -> String {
+    ident(&snake_case(method))
+}
+
+/// The names defined in the generated code and what they are for, to find
+/// the ones defined twice.
+struct Names(HashMap<String, String>);
+
+impl Names {
+    fn new() -> Names {
+        Names(HashMap::new())
+    }
+
+    fn add(&mut self, name: String, origin: String) -> Result<(), Error> {
+        match self.0.entry(name) {
+            Entry::Occupied(e) => {
+                Err(Error::Collision(format!("`{}` is used for both {} and {}",
+                                             e.key(),
+                                             e.get(),
+                                             origin)))
+            }
+            Entry::Vacant(e) => {
+                e.insert(origin);
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds the struct `name` and the anonymous types of its fields, named
+    /// like in `struct_to_rust`.
+    fn add_struct(&mut self, name: String, origin: String, v: &VStruct) -> Result<(), Error> {
+        let mut fields = Names::new();
+        for e in &v.elts {
+            fields.add(field_name(e.name), format!("field `{}` of `{}`", e.name, name))?;
+            let nested = format!("{}{}", name, camel_case(e.name));
+            let nested_origin = format!("field `{}` of `{}`", e.name, name);
+            match e.vtype.vtype {
+                VType::VStruct(ref v) => self.add_struct(nested, nested_origin, v)?,
+                VType::VEnum(ref v) => {
+                    check_variants(&nested, v)?;
+                    self.add(nested, nested_origin)?
+                }
+                _ => {}
+            }
+        }
+        self.add(name, origin)
+    }
+}
+
+/// Checks that the values of the enum `name` have unique variants, named
+/// like in `enum_to_rust`.
+fn check_variants(name: &str, v: &VEnum) -> Result<(), Error> {
+    let mut variants = Names::new();
+    for elt in &v.elts {
+        variants.add(ident(&camel_case(elt)),
+                     format!("value `{}` of `{}`", elt, name))?;
+    }
+    Ok(())
+}
+
+/// Checks that the types, enum variants and functions generated for `iface`
+/// have unique names. Anonymous types are named after their parents, which
+/// valid descriptions may clash with.
+fn check_names(iface: &Interface) -> Result<(), Error> {
+    let mut types = Names::new();
+    for name in &["Error", "Interface", "Server", "Client"] {
+        types.add(name.to_string(), format!("the generated `{}`", name))?;
+    }
+    for t in iface.typedefs.values() {
+        match t.elt {
+            VStructOrEnum::VStruct(ref v) => {
+                types.add_struct(t.name.into(), format!("type `{}`", t.name), v)?
+            }
+            VStructOrEnum::VEnum(ref v) => {
+                check_variants(t.name, v)?;
+                types.add(t.name.into(), format!("type `{}`", t.name))?
+            }
+        }
+    }
+    for t in iface.methods.values() {
+        types.add_struct(format!("{}Reply", t.name),
+                        format!("the reply of method `{}`", t.name),
+                        &t.output)?;
+        if !t.input.elts.is_empty() {
+            types.add_struct(format!("{}Args", t.name),
+                            format!("the parameters of method `{}`", t.name),
+                            &t.input)?;
+        }
+    }
+
+    // The functions of `Interface` and `Client`.
+    let mut fns = Names::new();
+    for t in iface.methods.values() {
+        fns.add(fn_name(t.name), format!("method `{}`", t.name))?;
+        fns.add(ident(&(snake_case(t.name) + "_more")),
+                format!("the streaming call of method `{}`", t.name))?;
+    }
+
+    let mut variants = Names::new();
+    variants.add("Varlink".into(), "the variant for other errors".into())?;
+    for t in iface.errors.values() {
+        if !t.parm.elts.is_empty() {
+            types.add_struct(format!("{}Error", t.name),
+                            format!("the parameters of error `{}`", t.name),
+                            &t.parm)?;
+        }
+        variants.add(t.name.into(), format!("error `{}`", t.name))?;
+    }
+    Ok(())
+}
+
+/// The field or argument for `name`, e.g. `if_index` for `ifIndex`.
+fn field_name(name: &str) -> String {
+    ident(&snake_case(name))
+}
+
+/// Quotes `s` as a raw string literal.
+fn raw_string(s: &str) -> String {
+    let mut hashes = "#".to_owned();
+    while s.contains(&format!("\"{}", hashes)) {
+        hashes += "#";
+    }
+    format!("r{}\"{}\"{}", hashes, s, hashes)
+}
+
+/// The implementation of `varlink::server::Interface`, which passes the
+/// calls on to the generated `Interface`.
+fn server_to_rust(v: &Varlink) -> String {
+    let iface = &v.interface;
+    let mut out: String = "".to_owned();
+
+    out += "/// Serves an implementation of `Interface`.\n";
+    out += "pub struct Server<T>(pub T);\n\n";
+    out += "pub fn new<T: Interface + 'static>(inner: T) -> Box<varlink::server::Interface> {\n";
+    out += "    Box::new(Server(inner))\n";
+    out += "}\n\n";
+
+    out += "impl<T: Interface> varlink::server::Interface for Server<T> {\n";
+    out += "    fn get_description(&self) -> &'static str {\n";
+    out += format!("        {}\n", raw_string(v.string)).as_ref();
+    out += "    }\n\n";
+    out += "    fn get_name(&self) -> &'static str {\n";
+    out += format!("        \"{}\"\n", iface.name).as_ref();
+    out += "    }\n\n";
+    out += "    fn call(&self,\n";
+    out += "            req: varlink::server::Request)\n";
+    out += "            -> Result<serde_json::Value, varlink::server::Error> {\n";
+    out += "        match req.method.as_ref() {\n";
+    for t in iface.methods.values() {
+        out += format!("            \"{}.{}\" => {{\n", iface.name, t.name).as_ref();
+        let mut args: Vec<String> = Vec::new();
+        if !t.input.elts.is_empty() {
+            out += "                let mut parameters = varlink::server::parameters(req.parameters)?;\n";
+            out += format!("                let args = {}Args {{\n", t.name).as_ref();
+            for e in &t.input.elts {
+                out += format!("                    {}: varlink::server::parameter(&mut parameters, \"{}\")?,\n",
+                               field_name(e.name),
+                               e.name)
+                    .as_ref();
+                args.push(format!("args.{}", field_name(e.name)));
+            }
+            out += "                };\n";
+        }
+        out += format!("                let reply = self.0.{}({})?;\n",
+                       fn_name(t.name),
+                       args.join(", "))
+            .as_ref();
+        out += "                serde_json::to_value(reply).map_err(varlink::server::Error::invalid_reply)\n";
+        out += "            }\n";
+    }
+    out += "            m => Err(varlink::server::Error::method_not_found(m)),\n";
+    out += "        }\n";
+    out += "    }\n";
+    out += "}\n";
+    out
+}
+
+/// A client for the interface, on a `varlink::client::Connection`.
+fn client_to_rust(iface: &Interface) -> Result<String, Error> {
+    let mut out: String = "".to_owned();
+
+    out += "/// Calls the methods of the interface on a connection. The replies come\n";
+    out += "/// with the connection, for further calls.\n";
+    out += "pub struct Client<T>(pub varlink::client::Connection<T>);\n\n";
+    out += "impl<T> Client<T>\n";
+    out += "    where T: varlink::client::AsyncRead + varlink::client::AsyncWrite + 'static\n";
+    out += "{\n";
+    for t in iface.methods.values() {
+        let mut inparms: String = "".to_owned();
+        let mut fields: Vec<String> = Vec::new();
+        for e in &t.input.elts {
+            inparms += format!(", {}: {}", field_name(e.name), arg_type(t.name, e)?).as_ref();
+            fields.push(field_name(e.name));
+        }
+
+        for &(suffix, call, result) in
+            &[("", "call_typed", "TypedCall"), ("_more", "call_more_typed", "TypedReplies")] {
+            let name = ident(&(snake_case(t.name) + suffix));
+            out += doc_to_rust(&t.doc, "    ///").as_ref();
+            out += format!("    pub fn {}(self{})\n", name, inparms).as_ref();
+            out += format!("        -> varlink::client::{}<T, {}Reply, Error> {{\n",
+                           result,
+                           t.name)
+                .as_ref();
+            out += "        let req = varlink::server::Request {\n";
+            out += format!("            method: \"{}.{}\".into(),\n", iface.name, t.name).as_ref();
+            out += "            ..Default::default()\n";
+            out += "        };\n";
+            if t.input.elts.is_empty() {
+                out += format!("        self.0.{}(req)\n", call).as_ref();
+            } else {
+                // The arguments are serialized by the call, which fails if
+                // they can't be.
+                out += format!("        self.0.{}_with(req, &{}Args {{ {} }})\n",
+                               call,
+                               t.name,
+                               fields.join(", "))
+                    .as_ref();
+            }
+            out += "    }\n\n";
+        }
+    }
+    // No blank line before the closing brace.
+    out.pop();
+    out += "}\n";
+    Ok(out)
+}
+
+/// Returns the Rust code for the interface `description`, to be included in
+/// a module with `include!`. The documentation of the interface is on the
+/// `Interface` trait.
+pub fn compile(description: &str) -> Result<String, Error> {
+    let vr = Varlink::from_string(description).map_err(Error::Parse)?;
+    check_names(&vr.interface)?;
+    Ok(format!(r#"
+use serde_json;
+use varlink;
+
+{}{}
+{}"#,
+               vr.interface.to_rust("", &mut Nested::new())?,
+               server_to_rust(&vr),
+               client_to_rust(&vr.interface)?))
+}
+
+/// Like `compile`, but for a module file of its own, which starts with the
+/// documentation of the interface as `//!` comments.
+pub fn compile_module(description: &str) -> Result<String, Error> {
+    let code = compile(description)?;
+    let vr = Varlink::from_string(description).map_err(Error::Parse)?;
+    Ok(doc_to_rust(&vr.interface.doc, "//!") + &code)
+}
+
+/// Reads the interface description from `input` and writes the Rust code for
+/// it to `output`, as a module file of its own, see `compile_module`.
+pub fn generate<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<(), Error> {
+    write_code(input.as_ref(), output.as_ref(), compile_module)
+}
+
+fn write_code(input: &Path,
+              output: &Path,
+              compile: fn(&str) -> Result<String, Error>)
+              -> Result<(), Error> {
+    let mut description = String::new();
+    File::open(input)?.read_to_string(&mut description)?;
+    let code = compile(&description)?;
+    File::create(output)?.write_all(code.as_bytes())?;
+    Ok(())
+}
+
+/// For `build.rs`: generates the code for the interface description at
+/// `input` into `OUT_DIR`, named after the file with `.` replaced by `_`,
+/// e.g. `org_example_ping.rs` for `org.example.ping.varlink`. Cargo is told
+/// to run the build script again if `input` changes.
+///
+/// The code is the one of `compile`, `include!` does not allow the `//!`
+/// documentation of a module file.
+///
+/// Panics with the error, like build scripts do.
+pub fn cargo_build<P: AsRef<Path>>(input: P) {
+    let input = input.as_ref();
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    let name = input
+        .file_stem()
+        .expect("no file name given")
+        .to_string_lossy()
+        .replace('.', "_");
+    let output = out_dir.join(name + ".rs");
+
+    if let Err(e) = write_code(input, &output, compile) {
+        panic!("could not generate code for {}: {}", input.display(), e);
+    }
+    println!("cargo:rerun-if-changed={}", input.display());
+}
+
+/// Checks that `output` is what `generate` writes for `input`. Returns a
+/// unified diff from `output` to the generated code if it is not.
+pub fn check<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<Option<String>, Error> {
+    let mut description = String::new();
+    File::open(input)?.read_to_string(&mut description)?;
+    let code = compile_module(&description)?;
+    let mut existing = String::new();
+    File::open(output.as_ref())?
+        .read_to_string(&mut existing)?;
+    let name = output.as_ref().display().to_string();
+    Ok(diff(&name, &existing, &name, &code))
+}
+
+/// A unified diff of the lines from `old` to `new`, or `None` if they are
+/// the same.
+pub fn diff(old_name: &str, old: &str, new_name: &str, new: &str) -> Option<String> {
+    const CONTEXT: usize = 3;
+
+    if old == new {
+        return None;
+    }
+    // The lines keep their `\n`, so that a missing one at the end is a change.
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+
+    // The length of the longest common subsequence of `a[i..]` and `b[j..]`.
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                ::std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    // The lines with ' ', '-' or '+' and their index in `a` and `b`.
+    let mut ops: Vec<(char, &str, usize, usize)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push((' ', a[i], i, j));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', a[i], i, j));
+            i += 1;
+        } else {
+            ops.push(('+', b[j], i, j));
+            j += 1;
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let mut k = 0;
+    while let Some(first) = ops[k..].iter().position(|op| op.0 != ' ') {
+        let first = first + k;
+        // Changes closer than twice the context go into the same hunk.
+        let mut last = first;
+        for (n, op) in ops.iter().enumerate().skip(first) {
+            if n - last > 2 * CONTEXT {
+                break;
+            }
+            if op.0 != ' ' {
+                last = n;
+            }
+        }
+        let start = first.saturating_sub(CONTEXT);
+        let end = ::std::cmp::min(last + CONTEXT + 1, ops.len());
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|op| op.0 != '+').count();
+        let new_len = hunk.iter().filter(|op| op.0 != '-').count();
+        let old_start = if old_len == 0 { ops[start].2 } else { ops[start].2 + 1 };
+        let new_start = if new_len == 0 { ops[start].3 } else { ops[start].3 + 1 };
+        out += format!("@@ -{},{} +{},{} @@\n", old_start, old_len, new_start, new_len).as_ref();
+        for op in hunk {
+            out += format!("{}{}", op.0, op.1).as_ref();
+            if !op.1.ends_with('\n') {
+                out += "\n\\ No newline at end of file\n";
+            }
+        }
+        k = end;
+    }
+    Some(out)
+}
+
+#[test]
+fn test_generate() {
+    let dir = env::temp_dir().join(format!("varlink-generator-{}", ::std::process::id()));
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("org.example.ping.varlink");
+    let output = dir.join("org_example_ping.rs");
+    File::create(&input)
+        .unwrap()
+        .write_all(b"interface org.example.ping\nmethod Ping(ping: string) -> (pong: ?string)\n")
+        .unwrap();
+
+    generate(&input, &output).unwrap();
+    let mut code = String::new();
+    File::open(&output)
+        .unwrap()
+        .read_to_string(&mut code)
+        .unwrap();
+    assert!(code.contains("pub struct PingArgs {\n    pub ping: String,\n}"));
+    assert!(code.contains("pub struct PingReply {\n    #[serde(skip_serializing_if = \"Option::is_none\", default)]\n    pub pong: Option<String>,\n}"));
+    assert!(code.contains("    fn ping(&self, ping: String) -> Result<PingReply, Error>;\n"));
+
+    assert_eq!(check(&input, &output).unwrap(), None);
+    File::create(&output)
+        .unwrap()
+        .write_all(code.replace("pub struct PingArgs", "struct PingArgs").as_bytes())
+        .unwrap();
+    let diff = check(&input, &output).unwrap().unwrap();
+    assert!(diff.contains("\n-struct PingArgs {\n+pub struct PingArgs {\n"));
+
+    match compile("interface org.example.ping\nmethod Ping(") {
+        Err(Error::Parse(_)) => {}
+        _ => panic!("expected a parse error"),
+    }
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_golden() {
+    // Regenerate with `generate` after changes to the generated code.
+    assert_eq!(compile_module(include_str!("../tests/generated/org.example.ping.varlink"))
+                   .unwrap(),
+               include_str!("../tests/generated/org_example_ping.rs"));
+    assert_eq!(compile_module(include_str!("../tests/generated/org.example.nested.varlink"))
+                   .unwrap(),
+               include_str!("../tests/generated/org_example_nested.rs"));
+    assert_eq!(compile_module(include_str!("../tests/generated/org.example.keywords.varlink"))
+                   .unwrap(),
+               include_str!("../tests/generated/org_example_keywords.rs"));
+}
+
+#[test]
+fn test_diff() {
+    assert_eq!(diff("a", "x\ny\n", "b", "x\ny\n"), None);
+    let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n";
+    let new = "1\n2\n3\n4\nfour\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n16\n";
+    assert_eq!(diff("a", old, "b", new).unwrap(),
+               "--- a\n+++ b\n\
+                @@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+four\n 6\n 7\n 8\n\
+                @@ -13,3 +13,4 @@\n 13\n 14\n 15\n+16\n");
+    assert_eq!(diff("a", "", "b", "x").unwrap(),
+               "--- a\n+++ b\n@@ -0,0 +1,1 @@\n+x\n\\ No newline at end of file\n");
+    assert_eq!(diff("a", "x\ny", "b", "x\ny\n").unwrap(),
+               "--- a\n+++ b\n@@ -1,2 +1,2 @@\n x\n-y\n\\ No newline at end of file\n+y\n");
+}
+
+#[test]
+fn test_deterministic() {
+    let description = include_str!("../tests/generated/org.example.nested.varlink");
+    let code = compile(description).unwrap();
+    for _ in 0..10 {
+        assert_eq!(compile(description).unwrap(), code);
+    }
+}
+
+#[test]
+fn test_compile_module() {
+    let description = include_str!("../tests/generated/org.example.ping.varlink");
+    assert_eq!(compile_module(description).unwrap(),
+               "//! An interface to test the generated code\n".to_owned() +
+               &compile(description).unwrap());
+}
+
+#[test]
+fn test_collisions() {
+    fn collision(members: &str) -> String {
+        match compile(&format!("interface org.example.collision\n{}\n", members)) {
+            Err(Error::Collision(e)) => e,
+            r => panic!("expected a collision for {:?}, got {:?}", members, r.map(|_| ())),
+        }
+    }
+
+    assert_eq!(collision("type Netdev (info: (a: int))\ntype NetdevInfo (b: int)\n\
+                          method Ping() -> ()"),
+               "`NetdevInfo` is used for both field `info` of `Netdev` and type `NetdevInfo`");
+    assert_eq!(collision("type InfoReplyAddress (y: int)\n\
+                          method Info() -> (address: (x: int))"),
+               "`InfoReplyAddress` is used for both type `InfoReplyAddress` and \
+                field `address` of `InfoReply`");
+    assert_eq!(collision("type PingArgs (a: int)\nmethod Ping(ping: string) -> ()"),
+               "`PingArgs` is used for both type `PingArgs` and \
+                the parameters of method `Ping`");
+    assert_eq!(collision("type PingReply (a: int)\nmethod Ping() -> ()"),
+               "`PingReply` is used for both type `PingReply` and the reply of method `Ping`");
+    assert_eq!(collision("type TimeoutError (a: int)\nmethod Ping() -> ()\n\
+                          error Timeout (seconds: int)"),
+               "`TimeoutError` is used for both type `TimeoutError` and \
+                the parameters of error `Timeout`");
+    assert_eq!(collision("type Client (a: int)\nmethod Ping() -> ()"),
+               "`Client` is used for both the generated `Client` and type `Client`");
+    assert_eq!(collision("method Ping() -> ()\nerror Varlink ()"),
+               "`Varlink` is used for both the variant for other errors and error `Varlink`");
+
+    assert_eq!(collision("type State (foo_bar, fooBar)\nmethod Ping() -> ()"),
+               "`FooBar` is used for both value `foo_bar` of `State` and value `fooBar` of `State`");
+    assert_eq!(collision("method Ping() -> (state: (up, up_))"),
+               "`Up` is used for both value `up` of `PingReplyState` and \
+                value `up_` of `PingReplyState`");
+    assert_eq!(collision("method GetIPAddress() -> ()\nmethod GetIpAddress() -> ()"),
+               "`get_ip_address` is used for both method `GetIPAddress` and \
+                method `GetIpAddress`");
+    assert_eq!(collision("method Foo() -> ()\nmethod FooMore() -> ()"),
+               "`foo_more` is used for both the streaming call of method `Foo` and \
+                method `FooMore`");
+
+    assert_eq!(collision("type Netdev (self: int, self_: int)\nmethod Ping() -> ()"),
+               "`self_` is used for both field `self` of `Netdev` and field `self_` of `Netdev`");
+    assert_eq!(collision("method Ping(fooBar: int, foo_bar: int) -> ()"),
+               "`foo_bar` is used for both field `fooBar` of `PingArgs` and \
+                field `foo_bar` of `PingArgs`");
+    assert_eq!(collision("method Ping() -> (info: (ifIndex: int, if_index: int))"),
+               "`if_index` is used for both field `ifIndex` of `PingReplyInfo` and \
+                field `if_index` of `PingReplyInfo`");
+
+    // Without parameters, there is no `TimeoutError` struct.
+    assert!(compile("interface org.example.collision\ntype TimeoutError (a: int)\n\
+                     method Ping() -> ()\nerror Timeout ()\n")
+                    .is_ok());
+}
+
+#[test]
+fn test_names() {
+    assert_eq!(snake_case("GetIPAddress"), "get_ip_address");
+    assert_eq!(snake_case("GetInfo"), "get_info");
+    assert_eq!(snake_case("Get2Addresses"), "get2_addresses");
+    assert_eq!(snake_case("IPv6"), "i_pv6");
+    assert_eq!(camel_case("no_carrier"), "NoCarrier");
+    assert_eq!(camel_case("_private"), "_Private");
+    assert_eq!(ident("type"), "r#type");
+    assert_eq!(ident("self"), "self_");
+    assert_eq!(ident("kind"), "kind");
+    assert_eq!(fn_name("Self"), "self_");
+    assert_eq!(field_name("ifIndex"), "if_index");
+    assert_eq!(field_name("type"), "r#type");
+}
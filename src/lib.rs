@@ -9,11 +9,22 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate bytes;
+#[macro_use]
 extern crate futures;
+extern crate futures_cpupool;
 extern crate tokio_io;
-extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_uds;
+extern crate libc;
+extern crate tokio_core;
 
 
+pub mod client;
+pub mod codec;
+pub mod generator;
+pub mod middleware;
+pub mod observer;
 pub mod parser;
+pub mod policy;
 pub mod server;
+pub mod validate;
@@ -0,0 +1,160 @@
+//! Behaviour shared by all interfaces of a service, like access checks,
+//! logging or metrics.
+//!
+//! Middleware added with `VarlinkService::add_middleware` sees every request
+//! before it is passed to the interface and can change the request, answer it
+//! itself or change the responses. The middleware added first sees the
+//! request first and the responses last.
+
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{stream, Stream};
+
+use observer::{Observer, Record};
+use server::{Error, Request, Response, Responses};
+
+pub trait Middleware {
+    /// Passes `req` on with `next.call`, or returns an error to answer it
+    /// without calling the interface.
+    fn call(&self, req: Request, next: Next) -> Result<Responses, Error>;
+}
+
+impl<F> Middleware for F
+    where F: Fn(Request, Next) -> Result<Responses, Error>
+{
+    fn call(&self, req: Request, next: Next) -> Result<Responses, Error> {
+        self(req, next)
+    }
+}
+
+/// The rest of the chain, ending with the interface.
+pub struct Next<'a> {
+    chain: &'a [Box<Middleware>],
+    dispatch: &'a Fn(Request) -> Responses,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(chain: &'a [Box<Middleware>], dispatch: &'a Fn(Request) -> Responses) -> Self {
+        Next {
+            chain: chain,
+            dispatch: dispatch,
+        }
+    }
+
+    pub fn call(self, req: Request) -> Responses {
+        let (middleware, rest) = match self.chain.split_first() {
+            Some(x) => x,
+            None => return (self.dispatch)(req),
+        };
+        let next = Next {
+            chain: rest,
+            dispatch: self.dispatch,
+        };
+        match middleware.call(req, next) {
+            Ok(responses) => responses,
+            Err(e) => Box::new(stream::once(Ok(Response::Err(e)))),
+        }
+    }
+}
+
+/// Passes the requests and responses to an observer, as they are at this
+/// point of the chain.
+pub struct Logging(pub Arc<Observer + Send + Sync>);
+
+impl Middleware for Logging {
+    fn call(&self, req: Request, next: Next) -> Result<Responses, Error> {
+        let connection = req.context.connection_id();
+        let method = req.method.clone();
+        self.0.observe(&Record::request(connection, &req));
+        let observer = self.0.clone();
+        Ok(Box::new(next.call(req)
+                        .inspect(move |resp| {
+                                     observer.observe(&Record::response(connection, &method, resp))
+                                 })))
+    }
+}
+
+/// Called with the method and the latency of each call.
+type Report = Fn(&str, Duration);
+
+/// Reports the time from a request to its last response.
+pub struct Latency {
+    report: Rc<Report>,
+}
+
+impl Latency {
+    /// `report` is called with the method and the latency of each call.
+    pub fn new<F: Fn(&str, Duration) + 'static>(report: F) -> Self {
+        Latency { report: Rc::new(report) }
+    }
+}
+
+impl Middleware for Latency {
+    fn call(&self, req: Request, next: Next) -> Result<Responses, Error> {
+        let start = Instant::now();
+        let method = req.method.clone();
+        let report = self.report.clone();
+        Ok(Box::new(next.call(req)
+                        .inspect(move |resp| if !resp.continues() {
+                                     report(&method, start.elapsed())
+                                 })))
+    }
+}
+
+#[test]
+fn test_middleware() {
+    use futures::Future;
+    use server::VarlinkService;
+    use std::cell::RefCell;
+    use tokio_service::Service;
+
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let mut service = VarlinkService::new("org.varlink".into(),
+                                          "test".into(),
+                                          "0.1".into(),
+                                          "http://varlink.org".into(),
+                                          vec![]);
+    {
+        let calls = calls.clone();
+        service.add_middleware(Latency::new(move |method, _| {
+                                                calls.borrow_mut().push(method.to_string())
+                                            }));
+    }
+    service.add_middleware(|mut req: Request, next: Next| {
+                               if req.method == "org.varlink.service.Info" {
+                                   req.method = "org.varlink.service.GetInfo".into();
+                               }
+                               Ok(next.call(req))
+                           });
+    service.add_middleware(|req: Request, next: Next| {
+                               if req.method == "org.varlink.service.GetInterfaceDescription" {
+                                   return Err(Error::permission_denied());
+                               }
+                               Ok(next.call(req))
+                           });
+
+    let call = |method: &str| {
+        let req = Request {
+            method: method.to_string().into(),
+            ..Default::default()
+        };
+        service
+            .call(req)
+            .wait()
+            .and_then(|responses| responses.collect().wait())
+            .unwrap()
+            .pop()
+    };
+    match call("org.varlink.service.Info") {
+        Some(Response::Ok(reply)) => assert_eq!(reply.parameters.unwrap()["product"], "test"),
+        _ => panic!("expected a reply"),
+    }
+    match call("org.varlink.service.GetInterfaceDescription") {
+        Some(Response::Err(e)) => assert_eq!(e.error, "org.varlink.service.PermissionDenied"),
+        _ => panic!("expected an error reply"),
+    }
+    assert_eq!(*calls.borrow(),
+               vec!["org.varlink.service.Info", "org.varlink.service.GetInterfaceDescription"]);
+}
@@ -0,0 +1,278 @@
+//! Observing the messages of a service, e.g. to log them.
+//!
+//! A `VarlinkService` is silent, unless an `Observer` is set with
+//! `VarlinkService::set_observer`. `Logger` is an observer which writes the
+//! messages to stderr or any other writer, as text or as JSON lines.
+
+use serde_json::{self, Value};
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use server::{Reply, Request, Response};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        };
+        f.write_str(s)
+    }
+}
+
+pub enum Event<'a> {
+    Request(&'a Request),
+    /// A response to a call of `method`.
+    Response {
+        method: &'a str,
+        response: &'a Response,
+    },
+    /// A response to a call of `method` which doesn't match the interface
+    /// description, see `ReplyValidation::Log`.
+    InvalidResponse { method: &'a str, message: &'a str },
+    /// The description of `interface` does not parse, so its calls are not
+    /// validated.
+    InvalidDescription {
+        interface: &'a str,
+        message: &'a str,
+    },
+}
+
+pub struct Record<'a> {
+    pub level: Level,
+    /// See `CallContext::connection_id`.
+    pub connection: usize,
+    pub event: Event<'a>,
+}
+
+impl<'a> Record<'a> {
+    /// Requests and replies are logged at `Debug`, errors at `Info`.
+    pub fn request(connection: usize, req: &'a Request) -> Self {
+        Record {
+            level: Level::Debug,
+            connection: connection,
+            event: Event::Request(req),
+        }
+    }
+
+    pub fn response(connection: usize, method: &'a str, resp: &'a Response) -> Self {
+        Record {
+            level: match *resp {
+                Response::Err(_) => Level::Info,
+                _ => Level::Debug,
+            },
+            connection: connection,
+            event: Event::Response {
+                method: method,
+                response: resp,
+            },
+        }
+    }
+
+    /// Mismatches of the replies are logged at `Warn`.
+    pub fn invalid_response(connection: usize, method: &'a str, message: &'a str) -> Self {
+        Record {
+            level: Level::Warn,
+            connection: connection,
+            event: Event::InvalidResponse {
+                method: method,
+                message: message,
+            },
+        }
+    }
+
+    /// An invalid interface description is logged at `Warn`. It is no
+    /// event of a connection, so `connection` is 0.
+    pub fn invalid_description(interface: &'a str, message: &'a str) -> Self {
+        Record {
+            level: Level::Warn,
+            connection: 0,
+            event: Event::InvalidDescription {
+                interface: interface,
+                message: message,
+            },
+        }
+    }
+
+    /// The method of the call and the message as it is sent, or the
+    /// description of an invalid response. For an invalid interface
+    /// description, the interface and the parse error.
+    pub fn message(&self) -> (&str, Value) {
+        let (method, msg) = match self.event {
+            Event::Request(req) => (req.method.as_ref(), serde_json::to_value(req)),
+            Event::Response { method, response } => {
+                let msg = match *response {
+                    Response::Ok(ref reply) |
+                    Response::Upgrade(ref reply, _) => serde_json::to_value(reply),
+                    Response::Err(ref e) => serde_json::to_value(Reply::from(e.clone())),
+                };
+                (method, msg)
+            }
+            Event::InvalidResponse { method, message } => (method, Ok(Value::from(message))),
+            Event::InvalidDescription { interface, message } => {
+                (interface, Ok(Value::from(message)))
+            }
+        };
+        (method, msg.unwrap_or(Value::Null))
+    }
+}
+
+pub trait Observer {
+    fn observe(&self, record: &Record);
+}
+
+/// Changes a message before it is logged, e.g. to hide secrets.
+pub type Redact = Box<Fn(&str, &mut Value) + Send + Sync>;
+
+/// Writes the records up to a maximum level, one per line.
+pub struct Logger {
+    level: Level,
+    json: bool,
+    redact: Option<Redact>,
+    out: Mutex<Box<Write + Send>>,
+}
+
+impl Logger {
+    /// Logs to stderr.
+    pub fn new(level: Level) -> Self {
+        Logger::to_writer(level, io::stderr())
+    }
+
+    pub fn to_writer<W: Write + Send + 'static>(level: Level, out: W) -> Self {
+        Logger {
+            level: level,
+            json: false,
+            redact: None,
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Writes each record as a JSON object with the fields `level`,
+    /// `connection` and `request` or `response`, instead of text.
+    pub fn json(&mut self, on: bool) -> &mut Self {
+        self.json = on;
+        self
+    }
+
+    /// Calls `redact` with the method and each message before it is logged.
+    pub fn redact<F>(&mut self, redact: F) -> &mut Self
+        where F: Fn(&str, &mut Value) + Send + Sync + 'static
+    {
+        self.redact = Some(Box::new(redact));
+        self
+    }
+}
+
+impl Observer for Logger {
+    fn observe(&self, record: &Record) {
+        if record.level > self.level {
+            return;
+        }
+        let (method, mut msg) = record.message();
+        if let Some(ref redact) = self.redact {
+            redact(method, &mut msg);
+        }
+        let kind = match record.event {
+            Event::Request(_) => "request",
+            Event::Response { .. } => "response",
+            Event::InvalidResponse { .. } => "invalid_response",
+            Event::InvalidDescription { .. } => "invalid_description",
+        };
+        let line = if self.json {
+            let mut obj = json!({"level": record.level.to_string(), "connection": record.connection});
+            obj[kind] = msg;
+            obj.to_string()
+        } else {
+            format!("varlink[{}] {} {}: {}", record.connection, record.level, kind, msg)
+        };
+        // Logging must not fail the call.
+        if let Ok(mut out) = self.out.lock() {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+use std::sync::Arc;
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_logger() {
+    use server::Error;
+
+    let req = Request {
+        method: "org.example.secret.Login".into(),
+        parameters: Some(json!({"user": "foo", "password": "bar"})),
+        ..Default::default()
+    };
+    let reply = Response::Ok(Reply::new(json!({"token": "baz"})));
+    let error = Response::Err(Error::invalid_parameter("user"));
+
+    let buf = Buffer::default();
+    let mut logger = Logger::to_writer(Level::Info, buf.clone());
+    logger.redact(|_, msg| if let Some(p) = msg.pointer_mut("/parameters/password") {
+                      *p = json!("***");
+                  });
+    logger.observe(&Record::request(1, &req));
+    logger.observe(&Record::response(1, &req.method, &reply));
+    logger.observe(&Record::response(1, &req.method, &error));
+    logger.observe(&Record::invalid_response(1, &req.method, "invalid field `token`"));
+    logger.observe(&Record::invalid_description("org.example.secret", "error at 2:1"));
+    assert_eq!(String::from_utf8(buf.0.lock().unwrap().clone()).unwrap(),
+               "varlink[1] info response: {\"error\":\"org.varlink.service.InvalidParameter\",\
+                \"parameters\":{\"parameter\":\"user\"}}\n\
+                varlink[1] warn invalid_response: \"invalid field `token`\"\n\
+                varlink[0] warn invalid_description: \"error at 2:1\"\n");
+
+    let buf = Buffer::default();
+    let mut logger = Logger::to_writer(Level::Trace, buf.clone());
+    logger
+        .json(true)
+        .redact(|_, msg| if let Some(p) = msg.pointer_mut("/parameters/password") {
+                    *p = json!("***");
+                });
+    logger.observe(&Record::request(2, &req));
+    logger.observe(&Record::response(2, &req.method, &reply));
+    let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<Value> = out.lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines,
+               vec![json!({
+                   "level": "debug",
+                   "connection": 2,
+                   "request": {
+                       "method": "org.example.secret.Login",
+                       "parameters": {"user": "foo", "password": "***"}
+                   }
+               }),
+                    json!({"level": "debug", "connection": 2, "response": {"parameters": {"token": "baz"}}})]);
+}
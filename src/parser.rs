@@ -22,11 +22,14 @@ pub enum VType<'a> {
 pub struct VTypeExt<'a> {
     pub vtype: VType<'a>,
     pub isarray: bool,
+    /// Declared with `?`, the value may be `null` or missing.
+    pub isoption: bool,
 }
 
 pub struct Argument<'a> {
     pub name: &'a str,
     pub vtype: VTypeExt<'a>,
+    pub doc: Vec<&'a str>,
 }
 
 pub struct VStruct<'a> {
@@ -40,6 +43,7 @@ pub struct VEnum<'a> {
 pub struct VError<'a> {
     pub name: &'a str,
     pub parm: VStruct<'a>,
+    pub doc: Vec<&'a str>,
 }
 
 pub enum VStructOrEnum<'a> {
@@ -50,12 +54,14 @@ pub enum VStructOrEnum<'a> {
 pub struct Typedef<'a> {
     pub name: &'a str,
     pub elt: VStructOrEnum<'a>,
+    pub doc: Vec<&'a str>,
 }
 
 pub struct Method<'a> {
     pub name: &'a str,
     pub input: VStruct<'a>,
     pub output: VStruct<'a>,
+    pub doc: Vec<&'a str>,
 }
 
 enum MethodOrTypedefOrError<'a> {
@@ -66,6 +72,7 @@ enum MethodOrTypedefOrError<'a> {
 
 pub struct Interface<'a> {
     pub name: &'a str,
+    pub doc: Vec<&'a str>,
     pub methods: BTreeMap<&'a str, Method<'a>>,
     pub typedefs: BTreeMap<&'a str, Typedef<'a>>,
     pub errors: BTreeMap<&'a str, VError<'a>>,
@@ -102,6 +109,9 @@ macro_rules! printVTypeExt {
 
 impl<'a> fmt::Display for VTypeExt<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.isoption {
+            write!(f, "?")?;
+        }
         match self.vtype {
             VType::Bool(ref v) => printVTypeExt!(self, f, v, "bool"),
             VType::Int(ref v) => printVTypeExt!(self, f, v, "int"),
@@ -182,9 +192,13 @@ impl<'a> fmt::Display for Interface<'a> {
 }
 
 impl<'a> Interface<'a> {
-    fn from_token(n: &'a str, mt: Vec<MethodOrTypedefOrError<'a>>) -> Interface<'a> {
+    fn from_token(n: &'a str,
+                  doc: Vec<&'a str>,
+                  mt: Vec<MethodOrTypedefOrError<'a>>)
+                  -> Interface<'a> {
         let mut i = Interface {
             name: n,
+            doc: doc,
             methods: BTreeMap::new(),
             typedefs: BTreeMap::new(),
             errors: BTreeMap::new(),
@@ -231,6 +245,34 @@ impl<'a> Interface<'a> {
     }
 }
 
+/// The comment lines directly in front of a declaration, without the `#`,
+/// from the whitespace and comments `s` before it. Comments separated from
+/// the declaration by an empty line are not part of its documentation.
+fn doc_lines(s: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = s.lines().collect();
+    // The indentation of the declaration itself.
+    if !s.ends_with('\n') {
+        lines.pop();
+    }
+    let mut doc: Vec<&str> = lines.into_iter()
+        .rev()
+        .map(|l| l.trim_start())
+        .take_while(|l| l.starts_with('#'))
+        .map(|l| {
+                 let l = &l[1..];
+                 l.strip_prefix(' ').unwrap_or(l)
+             })
+        .collect();
+    doc.reverse();
+    doc
+}
+
+/// The line and column, both starting at 1, of the syntax error in `s`, if
+/// it does not parse.
+pub fn error_position(s: &str) -> Option<(usize, usize)> {
+    VInterface(s).err().map(|e| (e.line, e.column))
+}
+
 pub struct Varlink<'a> {
     pub string: &'a str,
     pub interface: Interface<'a>,
@@ -308,6 +350,12 @@ error MethodNotImplemented (method: string)
     );
 }
 
+#[test]
+fn test_error_position() {
+    assert_eq!(error_position("interface org.example.a\nmethod F()->()"), None);
+    assert_eq!(error_position("interface org.example.a\nmethod F(a)->()"), Some((2, 11)));
+}
+
 #[test]
 fn test_one_method() {
     let v = Varlink::from_string("interface foo.bar\nmethod Foo()->()");
@@ -372,6 +420,54 @@ fn test_type_one_array() {
                 .is_err());
 }
 
+#[test]
+fn test_type_option() {
+    let v = Varlink::from_string("interface foo.bar\n type I (b:?bool, c:?string[])\nmethod F()->()")
+        .unwrap();
+    assert_eq!(v.interface.to_string(),
+               "interface foo.bar\ntype I (b: ?bool, c: ?string[])\nmethod F() -> ()\n");
+    assert!(Varlink::from_string("interface foo.bar\n type I (b:??bool)\nmethod F()->()").is_err());
+    assert!(Varlink::from_string("interface foo.bar\n type I (b:bool?)\nmethod F()->()").is_err());
+}
+
+#[test]
+fn test_doc() {
+    let v = Varlink::from_string("
+# A header, which is no documentation
+
+# The interface
+#
+#   indented
+interface foo.bar
+
+# A type
+type T (
+  # A field
+  a: int,
+  b: int
+)
+
+# Not for F
+
+method F() -> ()
+	# An error
+	error E ()
+")
+            .unwrap();
+    let i = &v.interface;
+    assert_eq!(i.doc, vec!["The interface", "", "  indented"]);
+    assert_eq!(i.typedefs["T"].doc, vec!["A type"]);
+    match i.typedefs["T"].elt {
+        VStructOrEnum::VStruct(ref v) => {
+            assert_eq!(v.elts[0].doc, vec!["A field"]);
+            assert!(v.elts[1].doc.is_empty());
+        }
+        _ => panic!("expected a struct"),
+    }
+    assert!(i.methods["F"].doc.is_empty());
+    assert_eq!(i.errors["E"].doc, vec!["An error"]);
+}
+
 #[test]
 fn test_format() {
     let v = Varlink::from_string("interface foo.bar\ntype I(b:bool[])\nmethod  F()->()").unwrap();
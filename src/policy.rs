@@ -0,0 +1,290 @@
+//! Access control for the methods of a service.
+//!
+//! A `Policy` is a list of rules, each matching method names by a pattern:
+//! `io.systemd.network.Info` for a single method, `io.systemd.network.*` for
+//! all methods of an interface or `*` for all methods. The first rule that
+//! matches a call decides whether it is permitted. Calls matched by no rule
+//! are denied, unless the default is changed with `Policy::set_default` or
+//! the `default` key.
+//!
+//! Policies can be loaded from JSON:
+//!
+//! ```json
+//! {
+//!   "default": "deny",
+//!   "rules": [
+//!     { "method": "io.systemd.network.List", "uids": [0], "gids": [10] },
+//!     { "method": "io.systemd.network.*", "uids": [0] }
+//!   ]
+//! }
+//! ```
+
+use serde_json;
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::{future, stream, Future};
+
+use tokio_service::Service;
+
+use libc::{gid_t, uid_t};
+
+use server::{Error, Request, Response, Responses};
+
+/// Decides whether a call is permitted, see `Policy::allow_if`.
+pub type Check = Box<Fn(&Request) -> bool + Send + Sync>;
+
+#[derive(Deserialize)]
+struct Rule {
+    method: String,
+    #[serde(default)]
+    uids: Vec<uid_t>,
+    #[serde(default)]
+    gids: Vec<gid_t>,
+    #[serde(skip)]
+    check: Option<Check>,
+}
+
+impl Rule {
+    fn matches(&self, method: &str) -> bool {
+        if self.method == "*" {
+            return true;
+        }
+        if self.method.ends_with(".*") {
+            let iface = &self.method[..self.method.len() - 1];
+            return method.starts_with(iface) && !method[iface.len()..].contains('.');
+        }
+        self.method == method
+    }
+
+    fn permits(&self, req: &Request) -> bool {
+        if let Some(ref check) = self.check {
+            return check(req);
+        }
+        match req.context.peer_credentials() {
+            Some(cred) => self.uids.contains(&cred.uid) || self.gids.contains(&cred.gid),
+            None => false,
+        }
+    }
+}
+
+/// The decision for calls matched by no rule, `"allow"` or `"deny"` in JSON.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Deny
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct Policy {
+    #[serde(default)]
+    default: Access,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Policy::default()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    /// Sets whether calls matched by no rule are permitted, they are denied
+    /// by default.
+    pub fn set_default(&mut self, access: Access) -> &mut Self {
+        self.default = access;
+        self
+    }
+
+    /// Adds a rule permitting calls of the methods matching `pattern` to
+    /// peers running as one of `uids` or with one of `gids` as group.
+    pub fn allow(&mut self, pattern: &str, uids: &[uid_t], gids: &[gid_t]) -> &mut Self {
+        self.rules
+            .push(Rule {
+                      method: pattern.into(),
+                      uids: uids.to_vec(),
+                      gids: gids.to_vec(),
+                      check: None,
+                  });
+        self
+    }
+
+    /// Adds a rule permitting calls of the methods matching `pattern` if
+    /// `check` returns true. The caller is described by `req.context`.
+    pub fn allow_if<F>(&mut self, pattern: &str, check: F) -> &mut Self
+        where F: Fn(&Request) -> bool + Send + Sync + 'static
+    {
+        self.rules
+            .push(Rule {
+                      method: pattern.into(),
+                      uids: Vec::new(),
+                      gids: Vec::new(),
+                      check: Some(Box::new(check)),
+                  });
+        self
+    }
+
+    pub fn permits(&self, req: &Request) -> bool {
+        match self.rules.iter().find(|r| r.matches(&req.method)) {
+            Some(rule) => rule.permits(req),
+            None => self.default == Access::Allow,
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Passes only the calls permitted by a `Policy` to the inner service, the
+/// others are answered with `org.varlink.service.PermissionDenied`. The
+/// credentials checked by the rules of `Policy::allow` are only known on
+/// connections served with `server::serve_with_peer`.
+pub struct PolicyService<S> {
+    policy: Arc<Policy>,
+    inner: S,
+}
+
+impl<S> PolicyService<S> {
+    /// The policy can be shared by the services of all connections.
+    pub fn new(policy: Arc<Policy>, inner: S) -> Self {
+        PolicyService {
+            policy: policy,
+            inner: inner,
+        }
+    }
+}
+
+impl<S> Service for PolicyService<S>
+    where S: Service<Request = Request, Response = Responses, Error = io::Error>,
+          S::Future: 'static
+{
+    type Request = Request;
+    type Response = Responses;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if self.policy.permits(&req) {
+            return Box::new(self.inner.call(req));
+        }
+        let denied = Response::Err(Error::permission_denied());
+        Box::new(future::ok(Box::new(stream::once(Ok(denied))) as Responses))
+    }
+}
+
+#[test]
+fn test_from_str() {
+    let policy: Policy = r#"
+{
+  "default": "allow",
+  "rules": [
+    { "method": "io.systemd.network.List", "uids": [0], "gids": [10] },
+    { "method": "io.systemd.network.*", "uids": [0] }
+  ]
+}
+"#
+            .parse()
+            .unwrap();
+    assert_eq!(policy.default, Access::Allow);
+    assert_eq!(policy.rules.len(), 2);
+    assert_eq!(policy.rules[0].gids, vec![10]);
+    assert!(policy.rules[1].matches("io.systemd.network.Info"));
+    assert!(!policy.rules[1].matches("io.systemd.network.foo.Info"));
+    assert!(!policy.rules[1].matches("io.systemd.networkd.Info"));
+    assert!(!policy.rules[0].matches("io.systemd.network.Info"));
+
+    let policy: Policy = r#"{ "rules": [] }"#.parse().unwrap();
+    assert_eq!(policy.default, Access::Deny);
+    assert!(r#"{ "default": "maybe" }"#.parse::<Policy>().is_err());
+}
+
+#[test]
+fn test_default() {
+    let req = Request {
+        method: "org.varlink.service.GetInfo".into(),
+        ..Default::default()
+    };
+    let mut policy = Policy::new();
+    assert!(!policy.permits(&req));
+    policy.set_default(Access::Allow);
+    assert!(policy.permits(&req));
+    policy.allow("org.varlink.service.*", &[], &[]);
+    assert!(!policy.permits(&req));
+}
+
+#[test]
+fn test_policy_service() {
+    use client::Connection;
+    use server::{self, VarlinkService};
+    use tokio_core::reactor::Core;
+    use tokio_uds::UnixStream;
+
+    let uid = unsafe { ::libc::getuid() };
+    let mut policy = Policy::new();
+    policy
+        .allow("org.varlink.service.GetInfo", &[uid], &[])
+        .allow_if("org.varlink.service.GetInterfaceDescription",
+                  |req| req.parameters.as_ref().unwrap()["interface"] == "org.varlink.service");
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (server_io, client_io) = UnixStream::pair(&handle).unwrap();
+
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![]);
+    let service = PolicyService::new(Arc::new(policy), service);
+    handle.spawn(server::serve_with_peer(server_io, service).map_err(|e| panic!("{}", e)));
+
+    let call = |method: &str, parameters| {
+        Request {
+            method: method.to_string().into(),
+            parameters: parameters,
+            ..Default::default()
+        }
+    };
+    let client = Connection::new(client_io)
+        .call(call("org.varlink.service.GetInfo", None))
+        .and_then(move |(info, conn)| {
+            conn.call(call("org.varlink.service.GetInterfaceDescription",
+                           Some(json!({"interface": "org.varlink.service"}))))
+                .map(|(desc, conn)| (info, desc, conn))
+        })
+        .and_then(move |(info, desc, conn)| {
+            conn.call(call("org.varlink.service.GetInterfaceDescription",
+                           Some(json!({"interface": "org.example.other"}))))
+                .map(|(other, _)| vec![info, desc, other])
+        });
+    let replies = core.run(client).unwrap();
+
+    let errors: Vec<Option<String>> = replies
+        .into_iter()
+        .map(|r| match r {
+                 Response::Err(e) => Some(e.error.into_owned()),
+                 _ => None,
+             })
+        .collect();
+    assert_eq!(errors,
+               vec![None, None, Some("org.varlink.service.PermissionDenied".into())]);
+}
@@ -1,115 +1,860 @@
-use serde_json::{self, Value};
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
 
+use std::cmp;
 use std::convert::From;
-use std::io;
-use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use bytes::BytesMut;
-use bytes::BufMut;
 
-use futures::{future, Future, BoxFuture};
+use futures::{future, stream, Async, AsyncSink, Future, Poll, Sink, Stream};
+pub use futures_cpupool::CpuPool;
 
-use tokio_proto::pipeline::ServerProto;
 use tokio_service::Service;
-use tokio_io::codec::{Encoder, Decoder};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::codec::Framed;
+use tokio_io::codec::{Framed, FramedParts};
+use tokio_core::net::TcpStream;
+use tokio_uds::UnixStream;
+
+use libc;
+
+pub use codec::{NulJsonCodec, DEFAULT_MAX_MESSAGE_SIZE};
+use middleware::{Middleware, Next};
+use observer::{Observer, Record};
+use parser::Varlink;
+use validate;
+
+/// The replies of a monitor-style method, see `Interface::call_more`.
+pub type ReplyStream = Box<Stream<Item = Reply, Error = Error>>;
+
+/// All responses to a single request, in the order they are sent.
+pub type Responses = Box<Stream<Item = Response, Error = io::Error>>;
+
+/// The reply of an asynchronous method, see `AsyncInterface::call`.
+pub type ValueFuture = Box<Future<Item = Value, Error = Error>>;
+
+/// The reply of an asynchronous upgrade, see `AsyncInterface::call_upgrade`.
+pub type UpgradeFuture = Box<Future<Item = (Value, Option<Box<Upgrade>>), Error = Error>>;
 
 pub trait Interface {
     fn get_description(&self) -> &'static str;
     fn get_name(&self) -> &'static str;
     fn call(&self, Request) -> Result<Value, Error>;
+
+    /// Called instead of `call` for requests with `more` set. Every reply but
+    /// the last one has to have `continues` set, a stream which ends after a
+    /// reply with `continues` is answered with
+    /// `org.varlink.service.InternalError`. By default the method is answered
+    /// with the single reply of `call`.
+    fn call_more(&self, req: Request) -> Result<ReplyStream, Error> {
+        let reply = self.call(req).map(Reply::new);
+        Ok(Box::new(stream::once(reply)))
+    }
+
+    /// Called instead of `call` for requests with `upgrade` set. After the
+    /// reply has been sent, the connection is handed over to the returned
+    /// `Upgrade` and no further varlink messages are read from it. Without an
+    /// `Upgrade` the connection stays a varlink connection, which is what the
+    /// default implementation does with the reply of `call`.
+    fn call_upgrade(&self, req: Request) -> Result<(Value, Option<Box<Upgrade>>), Error> {
+        self.call(req).map(|val| (val, None))
+    }
+}
+
+/// An interface whose methods complete asynchronously, without blocking the
+/// event loop of the service while waiting for I/O.
+///
+/// The methods correspond to the ones of `Interface`, which is the simpler
+/// choice for methods which return immediately.
+pub trait AsyncInterface {
+    fn get_description(&self) -> &'static str;
+    fn get_name(&self) -> &'static str;
+    fn call(&self, Request) -> ValueFuture;
+
+    fn call_more(&self, req: Request) -> ReplyStream {
+        Box::new(self.call(req).map(Reply::new).into_stream())
+    }
+
+    fn call_upgrade(&self, req: Request) -> UpgradeFuture {
+        Box::new(self.call(req).map(|val| (val, None)))
+    }
+}
+
+/// Adapts an `Interface` to `AsyncInterface`, its futures are ready as soon
+/// as they are returned.
+struct SyncInterface(Box<Interface>);
+
+impl AsyncInterface for SyncInterface {
+    fn get_description(&self) -> &'static str {
+        self.0.get_description()
+    }
+
+    fn get_name(&self) -> &'static str {
+        self.0.get_name()
+    }
+
+    fn call(&self, req: Request) -> ValueFuture {
+        Box::new(future::result(self.0.call(req)))
+    }
+
+    fn call_more(&self, req: Request) -> ReplyStream {
+        match self.0.call_more(req) {
+            Ok(replies) => replies,
+            Err(e) => Box::new(stream::once(Err(e))),
+        }
+    }
+
+    fn call_upgrade(&self, req: Request) -> UpgradeFuture {
+        Box::new(future::result(self.0.call_upgrade(req)))
+    }
+}
+
+/// Adapts an `Interface` whose methods block to `AsyncInterface` by calling
+/// them on a thread pool. Only `call` is run on the pool, `call_more` and
+/// `call_upgrade` are still called on the event loop. A method which panics is
+/// answered with `org.varlink.service.InternalError`.
+struct BlockingInterface {
+    iface: Arc<Interface + Send + Sync>,
+    pool: CpuPool,
+    /// The names of the blocking methods, without the interface name; all
+    /// methods block if unset.
+    methods: Option<HashSet<Cow<'static, str>>>,
+}
+
+impl BlockingInterface {
+    fn blocks(&self, req: &Request) -> bool {
+        match self.methods {
+            None => true,
+            Some(ref methods) => {
+                let n = req.method.rfind('.').map_or(0, |x| x + 1);
+                methods.contains(&req.method[n..])
+            }
+        }
+    }
+}
+
+impl AsyncInterface for BlockingInterface {
+    fn get_description(&self) -> &'static str {
+        self.iface.get_description()
+    }
+
+    fn get_name(&self) -> &'static str {
+        self.iface.get_name()
+    }
+
+    fn call(&self, req: Request) -> ValueFuture {
+        if self.blocks(&req) {
+            let iface = self.iface.clone();
+            Box::new(self.pool.spawn_fn(move || {
+                let method = req.method.clone();
+                panic::catch_unwind(AssertUnwindSafe(|| iface.call(req)))
+                    .unwrap_or_else(|cause| {
+                        let msg = cause
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| cause.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        Err(Error::internal_error(format!("{} panicked: {}", method, msg)))
+                    })
+            }))
+        } else {
+            Box::new(future::result(self.iface.call(req)))
+        }
+    }
+
+    fn call_more(&self, req: Request) -> ReplyStream {
+        match self.iface.call_more(req) {
+            Ok(replies) => replies,
+            Err(e) => Box::new(stream::once(Err(e))),
+        }
+    }
+
+    fn call_upgrade(&self, req: Request) -> UpgradeFuture {
+        Box::new(future::result(self.iface.call_upgrade(req)))
+    }
+}
+
+/// A connection after an upgrade, readable and writable with a custom
+/// protocol.
+pub trait RawTransport: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> RawTransport for T {}
+
+/// Takes over a connection, see `Interface::call_upgrade`.
+pub trait Upgrade {
+    fn upgrade(self: Box<Self>,
+               io: Box<RawTransport>)
+               -> Box<Future<Item = (), Error = io::Error>>;
+}
+
+impl<F> Upgrade for F
+    where F: FnOnce(Box<RawTransport>) -> Box<Future<Item = (), Error = io::Error>>
+{
+    fn upgrade(self: Box<Self>,
+               io: Box<RawTransport>)
+               -> Box<Future<Item = (), Error = io::Error>> {
+        (*self)(io)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// The underlying I/O object of an upgraded connection. Reads first return
+/// the bytes the peer sent after the upgrade request, which had already been
+/// buffered by the varlink framing.
+pub struct Upgraded<T> {
+    buffered: BytesMut,
+    io: T,
+}
+
+impl<T> Upgraded<T> {
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+/// The write buffer of `parts` is dropped, the transport has to be flushed
+/// before.
+impl<T> From<FramedParts<T>> for Upgraded<T> {
+    fn from(parts: FramedParts<T>) -> Self {
+        Upgraded {
+            buffered: parts.readbuf,
+            io: parts.inner,
+        }
+    }
+}
+
+impl<T: Read> Read for Upgraded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffered.is_empty() {
+            return self.io.read(buf);
+        }
+        let n = cmp::min(buf.len(), self.buffered.len());
+        buf[..n].copy_from_slice(&self.buffered.split_to(n));
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Upgraded<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Upgraded<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Upgraded<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct Request {
     pub method: Cow<'static, str>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub parameters: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub more: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub oneway: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub upgrade: Option<bool>,
+    /// Set by the server for the handlers, not part of the message.
+    #[serde(skip)]
+    pub context: CallContext,
+}
+
+/// The address of the peer of a connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerAddr {
+    Inet(SocketAddr),
+    /// The path the peer's socket is bound to, if any.
+    Unix(Option<PathBuf>),
+}
+
+/// The credentials of the process on the other end of a Unix socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Credentials {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Transports which can tell who is on the other end of the connection.
+pub trait PeerInfo {
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        None
+    }
+
+    fn peer_credentials(&self) -> Option<Credentials> {
+        None
+    }
+}
+
+impl PeerInfo for TcpStream {
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        TcpStream::peer_addr(self).ok().map(PeerAddr::Inet)
+    }
+}
+
+impl PeerInfo for UnixStream {
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        UnixStream::peer_addr(self)
+            .ok()
+            .map(|addr| PeerAddr::Unix(addr.as_pathname().map(PathBuf::from)))
+    }
+
+    fn peer_credentials(&self) -> Option<Credentials> {
+        peer_credentials(self.as_raw_fd()).ok()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: RawFd) -> io::Result<Credentials> {
+    use std::mem;
+
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(fd,
+                         libc::SOL_SOCKET,
+                         libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void,
+                         &mut len)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Credentials {
+           pid: cred.pid,
+           uid: cred.uid,
+           gid: cred.gid,
+       })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(_: RawFd) -> io::Result<Credentials> {
+    Err(io::Error::new(io::ErrorKind::Other, "SO_PEERCRED is not supported"))
+}
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Information about the connection a request arrived on, shared by all
+/// calls of the connection.
+#[derive(Clone, Default)]
+pub struct CallContext {
+    id: usize,
+    peer_addr: Option<PeerAddr>,
+    credentials: Option<Credentials>,
+    session: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl CallContext {
+    /// Creates the context of a new connection on `io`.
+    pub fn new<T: PeerInfo>(io: &T) -> Self {
+        CallContext {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr: io.peer_addr(),
+            credentials: io.peer_credentials(),
+            session: Default::default(),
+        }
+    }
+
+    /// Creates the context of a new connection on a transport which doesn't
+    /// know its peer.
+    pub fn without_peer() -> Self {
+        CallContext {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+
+    /// A number identifying the connection within the process, `0` for
+    /// requests which did not arrive on a connection.
+    pub fn connection_id(&self) -> usize {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> Option<&PeerAddr> {
+        self.peer_addr.as_ref()
+    }
+
+    /// The credentials of the peer, only known for Unix sockets.
+    pub fn peer_credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Returns the session value stored under `key` on this connection.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.session.lock().unwrap().get(key).cloned()
+    }
+
+    /// Stores a session value for later calls on this connection, returns
+    /// the previous value.
+    pub fn set<K: Into<String>>(&self, key: K, val: Value) -> Option<Value> {
+        self.session.lock().unwrap().insert(key.into(), val)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        self.session.lock().unwrap().remove(key)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// A reply message as it is sent, see the varlink specification: the
+/// `parameters` of the reply, whether more replies `continues` and for an
+/// error its name in `error`. Fields which are `None` are omitted.
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Reply {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<Cow<'static, str>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub parameters: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub continues: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+impl Reply {
+    pub fn new(parameters: Value) -> Self {
+        Reply {
+            parameters: Some(parameters),
+            ..Default::default()
+        }
+    }
+
+    /// A reply which announces further replies to the same request.
+    pub fn continues(parameters: Value) -> Self {
+        Reply {
+            parameters: Some(parameters),
+            continues: Some(true),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Error> for Reply {
+    fn from(e: Error) -> Self {
+        Reply {
+            error: Some(e.error),
+            parameters: e.parameters,
+            continues: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Error {
     pub error: Cow<'static, str>,
     pub parameters: Option<Value>,
+    /// A description of the cause for the logs, it is not sent.
+    pub message: Option<String>,
 }
 
-impl From<serde_json::Error> for Error {
-    fn from(e: serde_json::Error) -> Self {
+impl Error {
+    pub fn new<S: Into<Cow<'static, str>>>(error: S, parameters: Option<Value>) -> Self {
         Error {
-            error: "UnknownError".into(),
-            ..Default::default()
+            error: error.into(),
+            parameters: parameters,
+            message: None,
+        }
+    }
+
+    pub fn interface_not_found(interface: &str) -> Self {
+        Error::new("org.varlink.service.InterfaceNotFound",
+                   Some(json!({"interface": interface})))
+    }
+
+    /// `method` is not declared by its interface.
+    pub fn method_not_found(method: &str) -> Self {
+        Error::new("org.varlink.service.MethodNotFound",
+                   Some(json!({"method": short_name(method)})))
+    }
+
+    /// `method` is declared by its interface, but the service doesn't
+    /// implement it.
+    pub fn method_not_implemented(method: &str) -> Self {
+        Error::new("org.varlink.service.MethodNotImplemented",
+                   Some(json!({"method": short_name(method)})))
+    }
+
+    pub fn invalid_parameter(parameter: &str) -> Self {
+        Error::new("org.varlink.service.InvalidParameter",
+                   Some(json!({"parameter": parameter})))
+    }
+
+    pub fn permission_denied() -> Self {
+        Error::new("org.varlink.service.PermissionDenied", None)
+    }
+
+    /// The `parameters` of a call can't be deserialized into the arguments of
+    /// the method, `e` is kept as the message.
+    pub fn invalid_parameters(e: serde_json::Error) -> Self {
+        Error {
+            message: Some(e.to_string()),
+            ..Error::invalid_parameter("parameters")
+        }
+    }
+
+    /// The reply of a method can't be serialized, this is not the caller's
+    /// fault.
+    pub fn invalid_reply(e: serde_json::Error) -> Self {
+        Error::internal_error(format!("invalid reply: {}", e))
+    }
+
+    /// The service failed to handle the call, `message` is kept for the logs.
+    pub fn internal_error<S: Into<String>>(message: S) -> Self {
+        Error {
+            message: Some(message.into()),
+            ..Error::new("org.varlink.service.InternalError", None)
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// The `parameters` of a call as an object, see `parameter`.
+pub fn parameters(parameters: Option<Value>) -> Result<Map<String, Value>, Error> {
+    match parameters {
+        None | Some(Value::Null) => Ok(Map::new()),
+        Some(Value::Object(fields)) => Ok(fields),
+        Some(_) => Err(Error::invalid_parameter("parameters")),
+    }
+}
+
+/// Removes the field `name` from the `parameters` of a call and deserializes
+/// it, a missing field from `null`. If that fails, the field is named in the
+/// `InvalidParameter` error.
+pub fn parameter<T: DeserializeOwned>(parameters: &mut Map<String, Value>,
+                                      name: &str)
+                                      -> Result<T, Error> {
+    let val = parameters.remove(name).unwrap_or(Value::Null);
+    serde_json::from_value(val).map_err(|e| {
+                                            Error {
+                                                message: Some(e.to_string()),
+                                                ..Error::invalid_parameter(name)
+                                            }
+                                        })
+}
+
+fn short_name(method: &str) -> &str {
+    match method.rfind('.') {
+        Some(n) => &method[n + 1..],
+        None => method,
+    }
+}
+
 pub enum Response {
     Ok(Reply),
     Err(Error),
+    /// The reply to an upgrade request, after which the connection is handed
+    /// over.
+    Upgrade(Reply, Box<Upgrade>),
 }
 
-pub struct NulJsonCodec;
-
-impl Decoder for NulJsonCodec {
-    type Item = Request;
-    type Error = io::Error;
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
-        if let Some(i) = buf.iter().position(|&b| b == 0) {
-            // remove the serialized frame from the buffer.
+impl Response {
+    /// Whether more responses to the same request follow this one.
+    pub fn continues(&self) -> bool {
+        match *self {
+            Response::Ok(ref reply) => reply.continues == Some(true),
+            Response::Err(_) |
+            Response::Upgrade(..) => false,
+        }
+    }
+}
 
-            let line = buf.split_to(i);
-            //println!("got {:?}", line);
-            // Also remove the '0'
-            buf.split_to(1);
+impl From<Reply> for Response {
+    fn from(reply: Reply) -> Self {
+        match reply.error {
+            Some(error) => Response::Err(Error::new(error, reply.parameters)),
+            None => Response::Ok(reply),
+        }
+    }
+}
 
-            Ok(Some(serde_json::from_slice(&line)?))
-        } else {
-            Ok(None)
+impl From<Result<Value, Error>> for Response {
+    fn from(r: Result<Value, Error>) -> Self {
+        match r {
+            Ok(val) => Response::Ok(Reply::new(val)),
+            Err(e) => Response::Err(e),
         }
     }
 }
 
-impl Encoder for NulJsonCodec {
-    type Item = Response;
-    type Error = io::Error;
+/// Maximum number of requests of one connection which are processed
+/// concurrently, before reading further requests is paused.
+const MAX_IN_FLIGHT: usize = 32;
 
-    fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> io::Result<()> {
-        match msg {
-            Response::Ok(val) => {
-                println!("Response: {}", serde_json::to_string(&val).unwrap());
-                buf.extend(serde_json::to_vec(&val)?)
-            }
-            Response::Err(val) => {
-                println!("Response: {}", serde_json::to_string(&val).unwrap());
-                buf.extend(serde_json::to_vec(&val)?)
+enum Call<F> {
+    Calling(F),
+    Replying(Responses),
+}
+
+struct InFlight<F> {
+    call: Call<F>,
+    /// The client asked for no reply, the responses are discarded.
+    oneway: bool,
+    /// The client asked for a connection upgrade, no further requests are
+    /// read before the call is completed.
+    upgrade: bool,
+    /// The last response announced more responses.
+    continued: bool,
+}
+
+/// Serves the varlink requests of a single connection.
+///
+/// Requests may be pipelined; the responses are written in the order of the
+/// requests, all responses to a `more` request before the next request's.
+/// Requests with `oneway` set are processed in order as well, but their
+/// responses are not sent. After the reply to an `upgrade` request the
+/// connection is handed over to the `Upgrade` of the reply.
+pub struct Connection<T, S>
+    where S: Service
+{
+    transport: Option<Framed<T, NulJsonCodec>>,
+    service: S,
+    context: CallContext,
+    in_flight: VecDeque<InFlight<S::Future>>,
+    unsent: Option<Response>,
+    read_closed: bool,
+    upgrading: bool,
+    upgrade: Option<Box<Upgrade>>,
+    upgraded: Option<Box<Future<Item = (), Error = io::Error>>>,
+}
+
+/// Returns a future which serves `io` with `service` until the client
+/// closes the connection. The requests passed to `service` carry the
+/// `CallContext` of the connection, without a peer address or credentials.
+pub fn serve<T, S>(io: T, service: S) -> Connection<T, S>
+    where T: AsyncRead + AsyncWrite + 'static,
+          S: Service<Request = Request, Response = Responses, Error = io::Error>
+{
+    serve_with_codec(io, service, NulJsonCodec::default(), CallContext::without_peer())
+}
+
+/// Like `serve`, but the `CallContext` tells the peer of a TCP or Unix socket.
+pub fn serve_with_peer<T, S>(io: T, service: S) -> Connection<T, S>
+    where T: AsyncRead + AsyncWrite + PeerInfo + 'static,
+          S: Service<Request = Request, Response = Responses, Error = io::Error>
+{
+    let context = CallContext::new(&io);
+    serve_with_codec(io, service, NulJsonCodec::default(), context)
+}
+
+/// Like `serve`, but with a `codec` e.g. limiting the message size
+/// differently, and the `context` of the connection.
+pub fn serve_with_codec<T, S>(io: T,
+                              service: S,
+                              codec: NulJsonCodec,
+                              context: CallContext)
+                              -> Connection<T, S>
+    where T: AsyncRead + AsyncWrite + 'static,
+          S: Service<Request = Request, Response = Responses, Error = io::Error>
+{
+    Connection {
+        transport: Some(io.framed(codec)),
+        service: service,
+        context: context,
+        in_flight: VecDeque::new(),
+        unsent: None,
+        read_closed: false,
+        upgrading: false,
+        upgrade: None,
+        upgraded: None,
+    }
+}
+
+impl<T, S> Connection<T, S>
+    where T: AsyncRead + AsyncWrite + 'static,
+          S: Service<Request = Request, Response = Responses, Error = io::Error>
+{
+    fn transport(&mut self) -> &mut Framed<T, NulJsonCodec> {
+        self.transport
+            .as_mut()
+            .expect("varlink transport used after upgrade")
+    }
+
+    fn read_requests(&mut self) -> io::Result<()> {
+        while !self.read_closed && !self.upgrading && self.in_flight.len() < MAX_IN_FLIGHT {
+            match self.transport().poll()? {
+                Async::Ready(Some(Err(e))) => {
+                    // Nothing is known about the request, so it can't be
+                    // oneway or an upgrade.
+                    let responses = stream::once(Ok(Response::Err(e)));
+                    self.in_flight
+                        .push_back(InFlight {
+                                       call: Call::Replying(Box::new(responses)),
+                                       oneway: false,
+                                       upgrade: false,
+                                       continued: false,
+                                   });
+                }
+                Async::Ready(Some(Ok(mut req))) => {
+                    req.context = self.context.clone();
+                    let oneway = req.oneway == Some(true);
+                    let upgrade = req.upgrade == Some(true);
+                    let f = self.service.call(req);
+                    self.in_flight
+                        .push_back(InFlight {
+                                       call: Call::Calling(f),
+                                       oneway: oneway,
+                                       upgrade: upgrade,
+                                       continued: false,
+                                   });
+                    // Anything after an upgrade request might be meant for
+                    // the upgraded protocol already.
+                    self.upgrading = upgrade;
+                }
+                Async::Ready(None) => self.read_closed = true,
+                Async::NotReady => break,
             }
+        }
+        Ok(())
+    }
 
+    fn poll_calls(&mut self) -> io::Result<()> {
+        for entry in self.in_flight.iter_mut() {
+            let responses = match entry.call {
+                Call::Calling(ref mut f) => {
+                    match f.poll()? {
+                        Async::Ready(responses) => responses,
+                        Async::NotReady => continue,
+                    }
+                }
+                Call::Replying(_) => continue,
+            };
+            entry.call = Call::Replying(responses);
         }
-        buf.put_u8(0);
         Ok(())
     }
+
+    fn send(&mut self, resp: Response) -> io::Result<bool> {
+        match self.transport().start_send(resp)? {
+            AsyncSink::Ready => Ok(true),
+            AsyncSink::NotReady(resp) => {
+                self.unsent = Some(resp);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Writes the responses of the oldest requests, returns whether any
+    /// request was completed.
+    fn write_responses(&mut self) -> io::Result<bool> {
+        let mut completed = false;
+
+        if let Some(resp) = self.unsent.take() {
+            if !self.send(resp)? {
+                return Ok(completed);
+            }
+        }
+
+        loop {
+            let (resp, oneway, upgrade) = match self.in_flight.front_mut() {
+                Some(&mut InFlight {
+                          call: Call::Replying(ref mut responses),
+                          oneway,
+                          upgrade,
+                          ref mut continued,
+                      }) => {
+                    match responses.poll()? {
+                        Async::Ready(Some(resp)) => {
+                            *continued = resp.continues();
+                            (resp, oneway, upgrade)
+                        }
+                        // The client waits for a reply without `continues`.
+                        Async::Ready(None) if *continued => {
+                            let e = Error::internal_error("the replies ended with `continues` set");
+                            (Response::Err(e), oneway, upgrade)
+                        }
+                        Async::Ready(None) => {
+                            self.complete();
+                            completed = true;
+                            continue;
+                        }
+                        Async::NotReady => return Ok(completed),
+                    }
+                }
+                _ => return Ok(completed),
+            };
+
+            let resp = match resp {
+                Response::Upgrade(reply, handler) => {
+                    if upgrade {
+                        self.upgrade = Some(handler);
+                    }
+                    Response::Ok(reply)
+                }
+                resp => resp,
+            };
+            if !resp.continues() {
+                self.complete();
+                completed = true;
+            }
+            if !oneway && !self.send(resp)? {
+                return Ok(completed);
+            }
+        }
+    }
+
+    fn complete(&mut self) {
+        if let Some(entry) = self.in_flight.pop_front() {
+            // An upgrade request without an `Upgrade` in the reply leaves the
+            // connection a varlink connection.
+            if entry.upgrade && self.upgrade.is_none() {
+                self.upgrading = false;
+            }
+        }
+    }
 }
 
-pub struct Proto;
+impl<T, S> Future for Connection<T, S>
+    where T: AsyncRead + AsyncWrite + 'static,
+          S: Service<Request = Request, Response = Responses, Error = io::Error>
+{
+    type Item = ();
+    type Error = io::Error;
 
-impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for Proto {
-    // For this protocol style, `Request` matches the `Item` type of the codec's `Encoder`
-    type Request = Request;
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        if let Some(ref mut upgraded) = self.upgraded {
+            return upgraded.poll();
+        }
 
-    // For this protocol style, `Response` matches the `Item` type of the codec's `Decoder`
-    type Response = Response;
+        loop {
+            self.read_requests()?;
+            self.poll_calls()?;
+            if !self.write_responses()? {
+                break;
+            }
+        }
 
-    // A bit of boilerplate to hook in the codec:
-    type Transport = Framed<T, NulJsonCodec>;
-    type BindTransport = Result<Self::Transport, io::Error>;
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(NulJsonCodec))
+        let flushed = self.transport().poll_complete()?.is_ready();
+
+        if flushed && self.unsent.is_none() {
+            if let Some(handler) = self.upgrade.take() {
+                let transport = self.transport.take().unwrap();
+                let io = Upgraded::from(transport.into_parts());
+                self.upgraded = Some(handler.upgrade(Box::new(io)));
+                return self.poll();
+            }
+            if self.read_closed && self.in_flight.is_empty() {
+                return Ok(Async::Ready(()));
+            }
+        }
+        Ok(Async::NotReady)
     }
 }
 
@@ -135,7 +880,29 @@ struct ServiceInfo {
 
 pub struct VarlinkService {
     info: ServiceInfo,
-    ifaces: HashMap<Cow<'static, str>, Box<Interface>>,
+    ifaces: HashMap<Cow<'static, str>, Box<AsyncInterface>>,
+    /// The parsed descriptions of the interfaces, including
+    /// `org.varlink.service`.
+    idl: HashMap<Cow<'static, str>, Rc<Varlink<'static>>>,
+    /// The interfaces with a description which does not parse, and the
+    /// parse error.
+    invalid_descriptions: Vec<(&'static str, String)>,
+    validate_parameters: bool,
+    reply_validation: ReplyValidation,
+    observer: Option<Arc<Observer + Send + Sync>>,
+    middleware: Vec<Box<Middleware>>,
+}
+
+/// What to do about replies which don't match the interface description, see
+/// `VarlinkService::set_reply_validation`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplyValidation {
+    Off,
+    /// Pass the mismatch to the observer at `Level::Warn`, the reply is sent
+    /// anyway.
+    Log,
+    /// Panic on a mismatch.
+    Panic,
 }
 
 impl VarlinkService {
@@ -145,82 +912,354 @@ impl VarlinkService {
                url: Cow<'static, str>,
                ifaces: Vec<Box<Interface>>)
                -> Self {
-        let mut ifhashmap = HashMap::<Cow<'static, str>, Box<Interface>>::new();
-        for i in ifaces {
-            ifhashmap.insert(i.get_name().into(), i);
-        }
-        let mut ifnames: Vec<Cow<'static, str>> = Vec::new();
-        ifnames.push("org.varlink.service".into());
-        ifnames.extend(ifhashmap
-                           .keys()
-                           .map(|i| Cow::<'static, str>::from(i.clone())));
-        VarlinkService {
+        let mut service = VarlinkService {
             info: ServiceInfo {
                 vendor: vendor,
                 product: product,
                 version: version,
                 url: url,
-                interfaces: ifnames,
-                ..Default::default()
+                interfaces: vec!["org.varlink.service".into()],
             },
-            ifaces: ifhashmap,
+            ifaces: HashMap::new(),
+            idl: HashMap::new(),
+            invalid_descriptions: Vec::new(),
+            validate_parameters: false,
+            reply_validation: ReplyValidation::Off,
+            observer: None,
+            middleware: Vec::new(),
+        };
+        let description = Interface::get_description(&service);
+        service.parse_description("org.varlink.service", description);
+        for i in ifaces {
+            service.add_interface(i);
+        }
+        service
+    }
+
+    /// Enables checking the parameters of each request against the method's
+    /// input in the interface description. Requests which don't match are
+    /// answered with `org.varlink.service.InvalidParameter`, without calling
+    /// the interface.
+    pub fn set_parameter_validation(&mut self, on: bool) {
+        self.validate_parameters = on;
+    }
+
+    /// Enables checking every reply and error against the interface
+    /// descriptions, meant for development builds. Replies must match the
+    /// output of the method, errors must be declared by an interface of the
+    /// service and their parameters must match.
+    pub fn set_reply_validation(&mut self, mode: ReplyValidation) {
+        self.reply_validation = mode;
+    }
+
+    /// Passes every request and response to `observer`, e.g. a `Logger`. The
+    /// observer can be shared by the services of all connections. Interface
+    /// descriptions which do not parse are reported to it as well.
+    pub fn set_observer(&mut self, observer: Arc<Observer + Send + Sync>) {
+        for &(name, ref message) in &self.invalid_descriptions {
+            observer.observe(&Record::invalid_description(name, message));
+        }
+        self.observer = Some(observer);
+    }
+
+    /// Adds `middleware` to the end of the chain every request passes before
+    /// it is dispatched to the interface.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Checks a response to a call of `method` against the interface
+    /// descriptions, returns a description of the mismatch.
+    pub fn check_response(&self, method: &str, resp: &Response) -> Result<(), String> {
+        check_response(&self.idl, method, resp)
+    }
+
+    /// Interfaces with a description which does not parse are not validated,
+    /// the parse error is reported to the observer.
+    fn parse_description(&mut self, name: &'static str, description: &'static str) {
+        match parse_cached(description) {
+            Ok(v) => {
+                self.idl.insert(name.into(), v);
+            }
+            Err(e) => {
+                self.idl.remove(name);
+                if let Some(ref observer) = self.observer {
+                    observer.observe(&Record::invalid_description(name, &e));
+                }
+                self.invalid_descriptions.push((name, e));
+            }
         }
     }
+
+    fn check_parameters(&self, req: &Request) -> Result<(), Error> {
+        let n = match req.method.rfind('.') {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let (iface, method) = (&req.method[..n], &req.method[n + 1..]);
+        let v = match self.idl.get(iface) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let m = match v.interface.methods.get(method) {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        validate::check_parameters(&v.interface, &m.input, req.parameters.as_ref())
+            .map_err(|parameter| Error::invalid_parameter(&parameter))
+    }
+
+    pub fn add_interface(&mut self, iface: Box<Interface>) {
+        self.add_async_interface(Box::new(SyncInterface(iface)));
+    }
+
+    /// Adds an interface whose methods block, they are called on `pool` so
+    /// they do not stall the other clients of the event loop. The pool can be
+    /// shared by the services of all connections.
+    pub fn add_blocking_interface(&mut self, iface: Arc<Interface + Send + Sync>, pool: CpuPool) {
+        self.add_async_interface(Box::new(BlockingInterface {
+                                              iface: iface,
+                                              pool: pool,
+                                              methods: None,
+                                          }));
+    }
+
+    /// Like `add_blocking_interface`, but only the given `methods` of the
+    /// interface, e.g. `"Info"`, are called on `pool`.
+    pub fn add_blocking_methods(&mut self,
+                                iface: Arc<Interface + Send + Sync>,
+                                pool: CpuPool,
+                                methods: &[&'static str]) {
+        let methods = methods.iter().map(|&m| m.into()).collect();
+        self.add_async_interface(Box::new(BlockingInterface {
+                                              iface: iface,
+                                              pool: pool,
+                                              methods: Some(methods),
+                                          }));
+    }
+
+    pub fn add_async_interface(&mut self, iface: Box<AsyncInterface>) {
+        let name = iface.get_name();
+        self.parse_description(name, iface.get_description());
+        if self.ifaces.insert(name.into(), iface).is_none() {
+            self.info.interfaces.push(name.into());
+        }
+    }
+}
+
+thread_local! {
+    /// The parsed interface descriptions, so that the service built for each
+    /// connection doesn't parse them again.
+    static DESCRIPTIONS: RefCell<HashMap<&'static str, Result<Rc<Varlink<'static>>, String>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Parses `description`, once per thread.
+fn parse_cached(description: &'static str) -> Result<Rc<Varlink<'static>>, String> {
+    DESCRIPTIONS.with(|descriptions| {
+        descriptions
+            .borrow_mut()
+            .entry(description)
+            .or_insert_with(|| Varlink::from_string(description).map(Rc::new))
+            .clone()
+    })
+}
+
+fn check_response(idl: &HashMap<Cow<'static, str>, Rc<Varlink<'static>>>,
+                  method: &str,
+                  resp: &Response)
+                  -> Result<(), String> {
+    match *resp {
+        Response::Ok(ref reply) |
+        Response::Upgrade(ref reply, _) => {
+            let n = match method.rfind('.') {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            let v = match idl.get(&method[..n]) {
+                Some(v) => v,
+                None => return Ok(()),
+            };
+            let m = match v.interface.methods.get(&method[n + 1..]) {
+                Some(m) => m,
+                None => return Err(format!("reply to the undeclared method `{}`", method)),
+            };
+            validate::check_parameters(&v.interface, &m.output, reply.parameters.as_ref())
+                .map_err(|field| format!("reply to `{}`: invalid field `{}`", method, field))
+        }
+        Response::Err(ref e) => {
+            let n = match e.error.rfind('.') {
+                Some(x) => x,
+                None => return Err(format!("error `{}` is not fully qualified", e.error)),
+            };
+            let verror = idl.get(&e.error[..n])
+                .and_then(|v| v.interface.errors.get(&e.error[n + 1..]).map(|e| (v, e)));
+            let (v, verror) = match verror {
+                Some(verror) => verror,
+                None => return Err(format!("error `{}` is not declared", e.error)),
+            };
+            validate::check_parameters(&v.interface, &verror.parm, e.parameters.as_ref())
+                .map_err(|field| format!("error `{}`: invalid parameter `{}`", e.error, field))
+        }
+    }
+}
+
+/// Calls `service` with `req` and panics unless all responses match the
+/// interface descriptions of the service. For tests of interfaces, the call
+/// has to complete without an event loop.
+pub fn assert_conformance(service: &VarlinkService, req: Request) -> Vec<Response> {
+    let method = req.method.clone();
+    let responses: Vec<Response> = Service::call(service, req)
+        .wait()
+        .and_then(|responses| responses.collect().wait())
+        .unwrap();
+    for resp in &responses {
+        if let Err(msg) = service.check_response(&method, resp) {
+            panic!("{}", msg);
+        }
+    }
+    responses
+}
+
+fn respond(f: ValueFuture) -> Responses {
+    Box::new(f.then(|r| Ok(Response::from(r))).into_stream())
+}
+
+fn respond_upgrade(f: UpgradeFuture) -> Responses {
+    Box::new(f.then(|r| {
+                        Ok(match r {
+                               Ok((val, Some(handler))) => {
+                                   Response::Upgrade(Reply::new(val), handler)
+                               }
+                               r => Response::from(r.map(|(val, _)| val)),
+                           })
+                    })
+                 .into_stream())
+}
+
+fn respond_more(replies: ReplyStream) -> Responses {
+    Box::new(replies.then(|r| match r {
+                              Ok(reply) => Ok(Response::Ok(reply)),
+                              Err(e) => Ok(Response::Err(e)),
+                          }))
 }
 
 impl Service for VarlinkService {
     // These types must match the corresponding protocol types:
     type Request = Request;
-    type Response = Response;
+    type Response = Responses;
 
     // For non-streaming protocols, service errors are always io::Error
     type Error = io::Error;
 
     // The future for computing the response; box it for simplicity.
-    type Future = BoxFuture<Self::Response, Self::Error>;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     // Produce a future for computing a response from a request.
     fn call(&self, req: Self::Request) -> Self::Future {
+        let method = req.method.clone();
+        let connection = req.context.connection_id();
+        if let Some(ref observer) = self.observer {
+            observer.observe(&Record::request(connection, &req));
+        }
+
+        let mut responses = Next::new(&self.middleware, &|req| self.dispatch(req)).call(req);
+
+        if self.reply_validation != ReplyValidation::Off {
+            let mode = self.reply_validation;
+            let idl = self.idl.clone();
+            let observer = self.observer.clone();
+            let method = method.clone();
+            responses = Box::new(responses.inspect(move |resp| {
+                let msg = match check_response(&idl, &method, resp) {
+                    Ok(()) => return,
+                    Err(msg) => msg,
+                };
+                if mode == ReplyValidation::Panic {
+                    panic!("{}", msg);
+                }
+                if let Some(ref observer) = observer {
+                    observer.observe(&Record::invalid_response(connection, &method, &msg));
+                }
+            }));
+        }
+
+        if let Some(observer) = self.observer.clone() {
+            responses = Box::new(responses.inspect(move |resp| {
+                observer.observe(&Record::response(connection, &method, resp))
+            }));
+        }
+
+        Box::new(future::ok(responses))
+    }
+}
 
-        println!("Request: {}", serde_json::to_string(&req).unwrap());
+impl VarlinkService {
+    fn dispatch(&self, req: Request) -> Responses {
         let n: usize = match req.method.rfind('.') {
             None => {
-                return future::ok(Response::Err(Error {
-                                                    error: "InterfaceNotFound".into(),
-                                                    parameters: Some(json!({"interface": req.method})),
-                                                    ..Default::default()
-                                                })).boxed()
+                let e = Error::interface_not_found(&req.method);
+                return respond(Box::new(future::err(e)));
             }
             Some(x) => x,
         };
+
+        // Without a parsed description, the interface decides whether it
+        // knows the method.
+        let declared = match self.idl.get(&req.method[..n]) {
+            Some(v) if !v.interface.methods.contains_key(&req.method[n + 1..]) => {
+                let e = Error::method_not_found(&req.method);
+                return respond(Box::new(future::err(e)));
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if self.validate_parameters {
+            if let Err(e) = self.check_parameters(&req) {
+                return respond(Box::new(future::err(e)));
+            }
+        }
+
         let method: String = req.method.clone().into();
         let (iface, _) = method.split_at(n);
 
-        match iface.as_ref() {
+        let responses = match iface {
             "org.varlink.service" => {
-                match self::Interface::call(self, req) {
-                    Ok(val) => future::ok(Response::Ok(Reply { parameters: Some(val) })).boxed(), 
-                    Err(e) => future::ok(Response::Err(e)).boxed(),
-                }
+                let reply = self::Interface::call(self, req);
+                respond(Box::new(future::result(reply)))
             }
             key => {
-                if self.ifaces.contains_key(key) {
-                    match self.ifaces[key].call(req) {
-                        Ok(val) => {
-                            future::ok(Response::Ok(Reply { parameters: Some(val) })).boxed()
+                match self.ifaces.get(key) {
+                    Some(iface) => {
+                        if req.upgrade == Some(true) {
+                            respond_upgrade(iface.call_upgrade(req))
+                        } else if req.more == Some(true) {
+                            respond_more(iface.call_more(req))
+                        } else {
+                            respond(iface.call(req))
                         }
-                        Err(e) => future::ok(Response::Err(e)).boxed(),
                     }
-                } else {
-                    future::ok(Response::Err(Error {
-                                                 error: "InterfaceNotFound".into(),
-                                                 parameters: Some(json!({"interface": key})),
-                                                 ..Default::default()
-                                             })).boxed()
+                    None => {
+                        let e = Error::interface_not_found(key);
+                        return respond(Box::new(future::err(e)));
+                    }
                 }
             }
+        };
 
+        if !declared {
+            return responses;
         }
+        // The method is declared, so an interface which doesn't know it
+        // just doesn't implement it.
+        Box::new(responses.map(move |resp| match resp {
+                                   Response::Err(ref e) if e.error ==
+                                                           "org.varlink.service.MethodNotFound" => {
+                                       Response::Err(Error::method_not_implemented(&method))
+                                   }
+                                   resp => resp,
+                               }))
     }
 }
 
@@ -256,6 +1295,12 @@ error MethodNotImplemented (method: string)
 
 # One of the passed parameters is invalid.
 error InvalidParameter (parameter: string)
+
+# Client is denied access
+error PermissionDenied ()
+
+# The service failed to handle the call, e.g. a method panicked.
+error InternalError ()
 	"#
     }
 
@@ -266,46 +1311,358 @@ error InvalidParameter (parameter: string)
     fn call(&self, req: Request) -> Result<Value, Error> {
         match req.method.as_ref() {
             "org.varlink.service.GetInfo" => {
-                return Ok(serde_json::to_value(&self.info)?);
+                serde_json::to_value(&self.info).map_err(Error::invalid_reply)
             }
             "org.varlink.service.GetInterfaceDescription" => {
-                if req.parameters == None {
-                    return Err(Error {
-                                   error: "InvalidParameter".into(),
-                                   ..Default::default()
-                               });
-                }
-                let args: GetInterfaceArgs = serde_json::from_value(req.parameters.unwrap())
-                    .unwrap();
+                let args: GetInterfaceArgs = match req.parameters {
+                    Some(parameters) => {
+                        serde_json::from_value(parameters)
+                            .map_err(|_| Error::invalid_parameter("interface"))?
+                    }
+                    None => return Err(Error::invalid_parameter("interface")),
+                };
                 match args.interface.as_ref() {
                     "org.varlink.service" => Ok(json!({"description": self.get_description()})),
                     key => {
                         if self.ifaces.contains_key(key) {
                             Ok(json!({"description": self.ifaces[key].get_description()}))
                         } else {
-                            Err(Error {
-                                    error: "InvalidParameter".into(),
-                                    parameters: Some(json!({"parameter": "interface"})),
-                                    ..Default::default()
-                                })
+                            Err(Error::invalid_parameter("interface"))
                         }
                     }
                 }
             }
-            m => {
-                let method: String = m.into();
-                let n: usize = match method.rfind('.') {
-                    None => 0,
-                    Some(x) => x + 1,
-                };
-                let (_, method) = method.split_at(n);
+            m => Err(Error::method_not_found(m)),
+        }
+    }
+}
 
-                Err(Error {
-                        error: "MethodNotFound".into(),
-                        parameters: Some(json!({"method": method})),
-                        ..Default::default()
-                    })
+#[cfg(test)]
+fn call_service<S>(service: &S, req: Request) -> Vec<Response>
+    where S: Service<Request = Request, Response = Responses, Error = io::Error>
+{
+    service
+        .call(req)
+        .wait()
+        .unwrap()
+        .wait()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn test_parameter_validation() {
+    let mut service = VarlinkService::new("org.varlink".into(),
+                                          "test".into(),
+                                          "0.1".into(),
+                                          "http://varlink.org".into(),
+                                          vec![]);
+    service.set_parameter_validation(true);
+
+    let describe = |parameters| {
+        Request {
+            method: "org.varlink.service.GetInterfaceDescription".into(),
+            parameters: Some(parameters),
+            ..Default::default()
+        }
+    };
+
+    match call_service(&service, describe(json!({"interface": "org.varlink.service"}))).pop() {
+        Some(Response::Ok(reply)) => assert!(reply.parameters.unwrap()["description"].is_string()),
+        _ => panic!("unexpected error reply"),
+    }
+
+    for parameters in [json!({"iface": "org.varlink.service"}),
+                       json!({"interface": 1}),
+                       json!({})] {
+        match call_service(&service, describe(parameters)).pop() {
+            Some(Response::Err(e)) => {
+                assert_eq!(e.error, "org.varlink.service.InvalidParameter");
+                assert_eq!(e.parameters.unwrap()["parameter"], "interface");
             }
+            _ => panic!("expected an error reply"),
         }
     }
 }
+
+#[cfg(test)]
+struct Partial;
+
+#[cfg(test)]
+impl Interface for Partial {
+    fn get_description(&self) -> &'static str {
+        "
+interface org.example.partial
+method Done() -> ()
+method Todo() -> ()
+"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.partial"
+    }
+
+    fn call(&self, req: Request) -> Result<Value, Error> {
+        match req.method.as_ref() {
+            "org.example.partial.Done" => Ok(json!({})),
+            m => Err(Error::method_not_found(m)),
+        }
+    }
+}
+
+#[test]
+fn test_method_errors() {
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![Box::new(Partial)]);
+    let error = |method: &str| {
+        let mut responses = call_service(&service,
+                                         Request {
+                                             method: method.to_string().into(),
+                                             ..Default::default()
+                                         });
+        match responses.pop() {
+            Some(Response::Err(e)) => (e.error.into_owned(), e.parameters.unwrap()),
+            _ => panic!("expected an error reply"),
+        }
+    };
+
+    assert_eq!(error("org.example.partial.Todo"),
+               ("org.varlink.service.MethodNotImplemented".into(), json!({"method": "Todo"})));
+    assert_eq!(error("org.example.partial.Undo"),
+               ("org.varlink.service.MethodNotFound".into(), json!({"method": "Undo"})));
+    assert_eq!(error("org.varlink.service.Undo"),
+               ("org.varlink.service.MethodNotFound".into(), json!({"method": "Undo"})));
+    assert_eq!(error("org.example.other.Undo"),
+               ("org.varlink.service.InterfaceNotFound".into(),
+                json!({"interface": "org.example.other"})));
+    assert_eq!(error("Undo"),
+               ("org.varlink.service.InterfaceNotFound".into(), json!({"interface": "Undo"})));
+    assert_eq!(error("org.varlink.service.GetInterfaceDescription"),
+               ("org.varlink.service.InvalidParameter".into(), json!({"parameter": "interface"})));
+}
+
+#[cfg(test)]
+struct Sloppy;
+
+#[cfg(test)]
+impl Interface for Sloppy {
+    fn get_description(&self) -> &'static str {
+        "
+interface org.example.sloppy
+method Info() -> (name: string, size: int)
+method Fail() -> ()
+method Crash() -> ()
+error NotFound (name: string)
+"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.sloppy"
+    }
+
+    fn call(&self, req: Request) -> Result<Value, Error> {
+        match req.method.as_ref() {
+            "org.example.sloppy.Info" => Ok(json!({"name": "foo", "sizee": 1})),
+            "org.example.sloppy.Crash" => Err(Error::new("org.example.sloppy.Crashed", None)),
+            _ => Err(Error::new("org.example.sloppy.NotFound", Some(json!({"name": "foo"})))),
+        }
+    }
+}
+
+#[cfg(test)]
+fn sloppy_service() -> VarlinkService {
+    VarlinkService::new("org.varlink".into(),
+                        "test".into(),
+                        "0.1".into(),
+                        "http://varlink.org".into(),
+                        vec![Box::new(Sloppy)])
+}
+
+#[test]
+fn test_assert_conformance() {
+    let service = sloppy_service();
+    for method in ["org.varlink.service.GetInfo", "org.example.sloppy.Fail"] {
+        assert_conformance(&service,
+                           Request {
+                               method: method.into(),
+                               ..Default::default()
+                           });
+    }
+}
+
+#[test]
+#[should_panic(expected = "reply to `org.example.sloppy.Info`: invalid field `size`")]
+fn test_assert_conformance_invalid_reply() {
+    assert_conformance(&sloppy_service(),
+                       Request {
+                           method: "org.example.sloppy.Info".into(),
+                           ..Default::default()
+                       });
+}
+
+#[cfg(test)]
+struct Panicking;
+
+#[cfg(test)]
+impl Interface for Panicking {
+    fn get_description(&self) -> &'static str {
+        "
+interface org.example.panicking
+method Panic() -> ()
+"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.panicking"
+    }
+
+    fn call(&self, _: Request) -> Result<Value, Error> {
+        panic!("don't");
+    }
+}
+
+#[test]
+fn test_assert_conformance_blocking_panic() {
+    let mut service = VarlinkService::new("org.varlink".into(),
+                                          "test".into(),
+                                          "0.1".into(),
+                                          "http://varlink.org".into(),
+                                          vec![]);
+    service.add_blocking_interface(Arc::new(Panicking), CpuPool::new(1));
+    let mut responses = assert_conformance(&service,
+                                           Request {
+                                               method: "org.example.panicking.Panic".into(),
+                                               ..Default::default()
+                                           });
+    match responses.pop() {
+        Some(Response::Err(e)) => assert_eq!(e.error, "org.varlink.service.InternalError"),
+        _ => panic!("expected an error reply"),
+    }
+}
+
+#[test]
+#[should_panic(expected = "error `org.example.sloppy.Crashed` is not declared")]
+fn test_reply_validation_panic() {
+    let mut service = sloppy_service();
+    service.set_reply_validation(ReplyValidation::Panic);
+    call_service(&service,
+                 Request {
+                     method: "org.example.sloppy.Crash".into(),
+                     ..Default::default()
+                 });
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct Warnings(Mutex<Vec<String>>);
+
+#[cfg(test)]
+impl Observer for Warnings {
+    fn observe(&self, record: &Record) {
+        use observer::Level;
+
+        if record.level == Level::Warn {
+            let (_, msg) = record.message();
+            self.0.lock().unwrap().push(msg.as_str().unwrap().into());
+        }
+    }
+}
+
+#[test]
+fn test_reply_validation_log() {
+    let warnings = Arc::new(Warnings::default());
+    let mut service = sloppy_service();
+    service.set_reply_validation(ReplyValidation::Log);
+    service.set_observer(warnings.clone());
+    call_service(&service,
+                 Request {
+                     method: "org.example.sloppy.Info".into(),
+                     ..Default::default()
+                 });
+    assert_eq!(*warnings.0.lock().unwrap(),
+               vec!["reply to `org.example.sloppy.Info`: invalid field `size`".to_string()]);
+}
+
+#[cfg(test)]
+struct Unparsable;
+
+#[cfg(test)]
+impl Interface for Unparsable {
+    fn get_description(&self) -> &'static str {
+        "
+interface org.example.unparsable
+method Done() -> ()
+method Broken(
+"
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.unparsable"
+    }
+
+    fn call(&self, req: Request) -> Result<Value, Error> {
+        match req.method.as_ref() {
+            "org.example.unparsable.Done" => Ok(json!({})),
+            m => Err(Error::method_not_found(m)),
+        }
+    }
+}
+
+#[test]
+fn test_invalid_description() {
+    let warnings = Arc::new(Warnings::default());
+    let mut service = VarlinkService::new("org.varlink".into(),
+                                          "test".into(),
+                                          "0.1".into(),
+                                          "http://varlink.org".into(),
+                                          vec![Box::new(Unparsable)]);
+    service.set_observer(warnings.clone());
+    assert_eq!(warnings.0.lock().unwrap().len(), 1);
+    assert!(service.idl.contains_key("org.varlink.service"));
+    assert!(!service.idl.contains_key("org.example.unparsable"));
+
+    // Also for interfaces added after the observer is set.
+    let mut service = VarlinkService::new("org.varlink".into(),
+                                          "test".into(),
+                                          "0.1".into(),
+                                          "http://varlink.org".into(),
+                                          vec![]);
+    service.set_observer(warnings.clone());
+    service.add_interface(Box::new(Unparsable));
+    let warnings = warnings.0.lock().unwrap();
+    assert_eq!(warnings.len(), 2);
+    assert_eq!(warnings[0], warnings[1]);
+}
+
+#[test]
+fn test_method_errors_invalid_description() {
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![Box::new(Unparsable)]);
+    let mut responses = call_service(&service,
+                                     Request {
+                                         method: "org.example.unparsable.Undo".into(),
+                                         ..Default::default()
+                                     });
+    match responses.pop() {
+        Some(Response::Err(e)) => {
+            assert_eq!(e.error, "org.varlink.service.MethodNotFound");
+            assert_eq!(e.parameters.unwrap(), json!({"method": "Undo"}));
+        }
+        _ => panic!("expected an error reply"),
+    }
+    match call_service(&service,
+                       Request {
+                           method: "org.example.unparsable.Done".into(),
+                           ..Default::default()
+                       })
+              .pop() {
+        Some(Response::Ok(_)) => {}
+        _ => panic!("expected a reply"),
+    }
+}
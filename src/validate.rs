@@ -0,0 +1,130 @@
+//! Checks the JSON values of varlink messages against the types declared in
+//! an interface description.
+
+use serde_json::{Map, Value};
+
+use parser::{Interface, VEnum, VStruct, VStructOrEnum, VType, VTypeExt};
+
+/// Checks the `parameters` of a message against `vstruct`. Returns the name
+/// of the first field which is missing, unknown or has the wrong type, or
+/// `"parameters"` if they are no object at all.
+pub fn check_parameters(iface: &Interface,
+                        vstruct: &VStruct,
+                        parameters: Option<&Value>)
+                        -> Result<(), String> {
+    let empty = Map::new();
+    let fields = match parameters {
+        None | Some(&Value::Null) => &empty,
+        Some(val) => val.as_object().ok_or_else(|| "parameters".to_string())?,
+    };
+    check_fields(iface, vstruct, fields)
+}
+
+fn check_fields(iface: &Interface,
+                vstruct: &VStruct,
+                fields: &Map<String, Value>)
+                -> Result<(), String> {
+    for arg in &vstruct.elts {
+        match fields.get(arg.name) {
+            Some(val) if matches_ext(iface, &arg.vtype, val) => {}
+            None if arg.vtype.isoption => {}
+            _ => return Err(arg.name.into()),
+        }
+    }
+    for name in fields.keys() {
+        if !vstruct.elts.iter().any(|arg| arg.name == name) {
+            return Err(name.clone());
+        }
+    }
+    Ok(())
+}
+
+fn matches_ext(iface: &Interface, vtype: &VTypeExt, val: &Value) -> bool {
+    if vtype.isoption && val.is_null() {
+        true
+    } else if vtype.isarray {
+        match *val {
+            Value::Array(ref elts) => elts.iter().all(|v| matches(iface, &vtype.vtype, v)),
+            _ => false,
+        }
+    } else {
+        matches(iface, &vtype.vtype, val)
+    }
+}
+
+fn matches(iface: &Interface, vtype: &VType, val: &Value) -> bool {
+    match *vtype {
+        VType::Bool(_) => val.is_boolean(),
+        VType::Int(_) => val.is_i64() || val.is_u64(),
+        VType::Float(_) => val.is_number(),
+        VType::VString(_) | VType::VData(_) => val.is_string(),
+        VType::VTypename(name) => {
+            match iface.typedefs.get(name) {
+                Some(t) => {
+                    match t.elt {
+                        VStructOrEnum::VStruct(ref v) => matches_struct(iface, v, val),
+                        VStructOrEnum::VEnum(ref v) => matches_enum(v, val),
+                    }
+                }
+                // Undeclared types can't be checked.
+                None => true,
+            }
+        }
+        VType::VStruct(ref v) => matches_struct(iface, v, val),
+        VType::VEnum(ref v) => matches_enum(v, val),
+    }
+}
+
+fn matches_struct(iface: &Interface, vstruct: &VStruct, val: &Value) -> bool {
+    match *val {
+        Value::Object(ref fields) => check_fields(iface, vstruct, fields).is_ok(),
+        _ => false,
+    }
+}
+
+fn matches_enum(venum: &VEnum, val: &Value) -> bool {
+    match val.as_str() {
+        Some(s) => venum.elts.contains(&s),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+use parser::Varlink;
+
+#[test]
+fn test_check_parameters() {
+    let v = Varlink::from_string("
+interface org.example.test
+type Netdev (ifindex: int, ifname: string, state: (up, down))
+method Set(netdevs: Netdev[], force: bool, reason: ?string) -> ()
+method List() -> (netdevs: Netdev[])
+")
+            .unwrap();
+    let set = &v.interface.methods["Set"].input;
+    let check = |val| check_parameters(&v.interface, set, Some(&val));
+
+    assert_eq!(check(json!({
+        "netdevs": [{"ifindex": 1, "ifname": "lo", "state": "up"}],
+        "force": false
+    })),
+               Ok(()));
+    assert_eq!(check(json!({"netdevs": [], "force": true, "reason": null})),
+               Ok(()));
+    assert_eq!(check(json!({"netdevs": [], "force": true, "reason": 1})),
+               Err("reason".into()));
+    assert_eq!(check(json!({"netdevs": []})), Err("force".into()));
+    assert_eq!(check(json!({"netdevs": [], "force": null})), Err("force".into()));
+    assert_eq!(check(json!({"netdevs": [], "force": 1})), Err("force".into()));
+    assert_eq!(check(json!({"netdevs": [], "force": true, "foo": 1})),
+               Err("foo".into()));
+    assert_eq!(check(json!({
+        "netdevs": [{"ifindex": 1, "ifname": "lo", "state": "sideways"}],
+        "force": true
+    })),
+               Err("netdevs".into()));
+    assert_eq!(check(json!([])), Err("parameters".into()));
+
+    let list = &v.interface.methods["List"].input;
+    assert_eq!(check_parameters(&v.interface, list, None), Ok(()));
+}
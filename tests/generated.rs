@@ -0,0 +1,253 @@
+//! Serves the code generated for `generated/org.example.ping.varlink` and
+//! checks the code generated for the other descriptions in `generated/`.
+
+extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_uds;
+extern crate varlink;
+
+#[allow(dead_code)]
+#[path = "generated/org_example_ping.rs"]
+mod org_example_ping;
+
+#[allow(dead_code)]
+#[path = "generated/org_example_nested.rs"]
+mod org_example_nested;
+
+#[allow(dead_code)]
+#[path = "generated/org_example_keywords.rs"]
+mod org_example_keywords;
+
+use org_example_ping::*;
+use varlink::server::{assert_conformance, Request, Response, VarlinkService};
+
+struct Pinger;
+
+impl Interface for Pinger {
+    fn ping(&self, ping: String, count: i64) -> Result<PingReply, Error> {
+        if ping.is_empty() {
+            return Err(Error::InvalidPing(InvalidPingError {
+                                              ping: ping,
+                                              reason: None,
+                                          }));
+        }
+        Ok(PingReply {
+               pong: Pong {
+                   ping: ping,
+                   count: count + 1,
+                   note: None,
+               },
+           })
+    }
+
+    fn pings(&self, pings: Vec<String>, note: Option<String>) -> Result<PingsReply, Error> {
+        Ok(PingsReply {
+               pongs: pings
+                   .into_iter()
+                   .map(|ping| {
+                            Pong {
+                                ping: ping,
+                                count: 1,
+                                note: note.clone(),
+                            }
+                        })
+                   .collect(),
+           })
+    }
+
+    fn reset(&self) -> Result<ResetReply, Error> {
+        Ok(ResetReply {})
+    }
+}
+
+fn call(method: &str, parameters: Option<serde_json::Value>) -> Response {
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![org_example_ping::new(Pinger)]);
+    let req = Request {
+        method: method.to_string().into(),
+        parameters: parameters,
+        ..Default::default()
+    };
+    assert_conformance(&service, req).pop().unwrap()
+}
+
+fn parameters(resp: Response) -> serde_json::Value {
+    match resp {
+        Response::Ok(reply) => reply.parameters.unwrap(),
+        Response::Err(e) => panic!("unexpected error {}", e.error),
+        _ => panic!("unexpected response"),
+    }
+}
+
+fn error(resp: Response) -> (String, serde_json::Value) {
+    match resp {
+        Response::Err(e) => (e.error.into_owned(), e.parameters.unwrap()),
+        _ => panic!("expected an error"),
+    }
+}
+
+#[test]
+fn test_dispatch() {
+    assert_eq!(parameters(call("org.example.ping.Ping", Some(json!({"ping": "a", "count": 1})))),
+               json!({"pong": {"ping": "a", "count": 2}}));
+    assert_eq!(parameters(call("org.example.ping.Pings", Some(json!({"pings": ["a", "b"]})))),
+               json!({"pongs": [{"ping": "a", "count": 1}, {"ping": "b", "count": 1}]}));
+    assert_eq!(parameters(call("org.example.ping.Pings", Some(json!({"pings": ["a"], "note": "n"})))),
+               json!({"pongs": [{"ping": "a", "count": 1, "note": "n"}]}));
+    assert_eq!(parameters(call("org.example.ping.Pings", Some(json!({"pings": ["a"], "note": null})))),
+               json!({"pongs": [{"ping": "a", "count": 1}]}));
+    assert_eq!(parameters(call("org.example.ping.Reset", None)), json!({}));
+
+    assert_eq!(error(call("org.example.ping.Ping", Some(json!({"ping": "a"})))),
+               ("org.varlink.service.InvalidParameter".into(), json!({"parameter": "count"})));
+    assert_eq!(error(call("org.example.ping.Ping", None)),
+               ("org.varlink.service.InvalidParameter".into(), json!({"parameter": "ping"})));
+    assert_eq!(error(call("org.example.ping.Pings", Some(json!({"pings": "a"})))),
+               ("org.varlink.service.InvalidParameter".into(), json!({"parameter": "pings"})));
+    assert_eq!(error(call("org.example.ping.Pings", Some(json!({"pings": ["a"], "note": 1})))),
+               ("org.varlink.service.InvalidParameter".into(), json!({"parameter": "note"})));
+    assert_eq!(error(call("org.example.ping.Pings", Some(json!(["a"])))),
+               ("org.varlink.service.InvalidParameter".into(), json!({"parameter": "parameters"})));
+    assert_eq!(error(call("org.example.ping.Pong", None)),
+               ("org.varlink.service.MethodNotFound".into(), json!({"method": "Pong"})));
+}
+
+#[test]
+fn test_errors() {
+    let (name, parameters) = error(call("org.example.ping.Ping", Some(json!({"ping": "", "count": 1}))));
+    assert_eq!(name, "org.example.ping.InvalidPing");
+    assert_eq!(parameters, json!({"ping": ""}));
+
+    // Back from the varlink error, e.g. in a client.
+    let e = varlink::server::Error::new(name, Some(parameters));
+    match Error::from(e) {
+        Error::InvalidPing(p) => assert_eq!((p.ping, p.reason), ("".into(), None)),
+        e => panic!("unexpected error {:?}", e),
+    }
+    let e = varlink::server::Error::new("org.example.ping.InvalidPing", Some(json!({"ping": 1})));
+    match Error::from(e) {
+        Error::Varlink(e) => assert_eq!(e.error, "org.example.ping.InvalidPing"),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_client() {
+    use futures::{Future, Stream};
+    use tokio_core::reactor::Core;
+    use tokio_uds::UnixStream;
+    use varlink::client::Connection;
+    use varlink::server;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (server_io, client_io) = UnixStream::pair(&handle).unwrap();
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![org_example_ping::new(Pinger)]);
+    handle.spawn(server::serve(server_io, service).map_err(|e| panic!("{}", e)));
+
+    let client = Client(Connection::new(client_io))
+        .ping("a".into(), 1)
+        .and_then(|(pong, conn)| Client(conn).ping("".into(), 1).map(|(e, conn)| (pong, e, conn)))
+        .and_then(|(pong, e, conn)| {
+                      Client(conn)
+                          .pings_more(vec!["b".into()], None)
+                          .collect()
+                          .map(|pongs| (pong, e, pongs))
+                  });
+    let (pong, e, pongs) = core.run(client).unwrap();
+
+    let pong = pong.unwrap().pong;
+    assert_eq!((pong.ping, pong.count), ("a".into(), 2));
+    match e {
+        Err(Error::InvalidPing(p)) => assert_eq!(p.ping, ""),
+        _ => panic!("expected InvalidPing"),
+    }
+    assert_eq!(pongs.len(), 1);
+    let pongs = pongs.into_iter().next().unwrap().unwrap().pongs;
+    assert_eq!(pongs[0].ping, "b");
+}
+
+#[test]
+fn test_nested() {
+    use org_example_nested::*;
+
+    let reply = InfoReply {
+        netdev: Netdev {
+            ifindex: 1,
+            state: NetdevState::NoCarrier,
+            link: Some(NetdevLink {
+                           speed: 1000,
+                           duplex: NetdevLinkDuplex::Full,
+                       }),
+        },
+        addresses: vec![InfoReplyAddresses {
+                            family: InfoReplyAddressesFamily::Inet6,
+                            address: "::1".into(),
+                        }],
+    };
+    let json = json!({
+        "netdev": {"ifindex": 1, "state": "no_carrier", "link": {"speed": 1000, "duplex": "full"}},
+        "addresses": [{"family": "inet6", "address": "::1"}]
+    });
+    assert_eq!(serde_json::to_value(&reply).unwrap(), json);
+
+    let args: InfoArgs = serde_json::from_value(json!({"ifindex": 1, "filter": {"up_only": true}}))
+        .unwrap();
+    assert!(args.filter.unwrap().up_only);
+    assert!(serde_json::from_value::<Netdev>(json!({"ifindex": 1, "state": "NoCarrier"})).is_err());
+}
+
+#[test]
+fn test_keywords() {
+    use org_example_keywords::*;
+
+    struct Keywords;
+
+    impl Interface for Keywords {
+        fn get_ip_address(&self,
+                          r#match: Match,
+                          r#where: Option<String>)
+                          -> Result<GetIPAddressReply, Error> {
+            Ok(GetIPAddressReply {
+                   r#loop: r#match.self_.is_some() && r#where.is_none(),
+                   if_index: r#match.self_,
+               })
+        }
+
+        fn r#type(&self, _: i64) -> Result<TypeReply, Error> {
+            Ok(TypeReply {})
+        }
+    }
+
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![org_example_keywords::new(Keywords)]);
+    let call = |method: &str, parameters| {
+        let req = Request {
+            method: method.to_string().into(),
+            parameters: Some(parameters),
+            ..Default::default()
+        };
+        assert_conformance(&service, req).pop().unwrap()
+    };
+    let m = json!({"type": "a", "self": 1, "ref": "no_op"});
+    assert_eq!(parameters(call("org.example.keywords.GetIPAddress", json!({"match": m}))),
+               json!({"loop": true, "ifIndex": 1}));
+    assert_eq!(parameters(call("org.example.keywords.Type", json!({"_": 1}))), json!({}));
+    assert_eq!(error(call("org.example.keywords.Type", json!({}))),
+               ("org.varlink.service.InvalidParameter".into(), json!({"parameter": "_"})));
+}
@@ -0,0 +1,183 @@
+//! Names which are no Rust identifiers as they are
+
+use serde_json;
+use varlink;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Match {
+    pub r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[serde(rename = "self")]
+    pub self_: Option<i64>,
+    pub r#ref: MatchRef,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MatchRef {
+    #[serde(rename = "move")]
+    Move,
+    #[serde(rename = "static")]
+    Static,
+    #[serde(rename = "no_op")]
+    NoOp,
+    #[serde(rename = "self")]
+    Self_,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetIPAddressReply {
+    pub r#loop: bool,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[serde(rename = "ifIndex")]
+    pub if_index: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetIPAddressArgs {
+    pub r#match: Match,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub r#where: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TypeReply {
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TypeArgs {
+    #[serde(rename = "_")]
+    pub __: i64,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Any other error, e.g. of `org.varlink.service`.
+    Varlink(varlink::server::Error),
+}
+
+impl From<Error> for varlink::server::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Varlink(e) => e,
+        }
+    }
+}
+
+/// Errors of this interface with parameters which don't match are kept
+/// as `Error::Varlink`.
+impl From<varlink::server::Error> for Error {
+    fn from(e: varlink::server::Error) -> Self {
+        Error::Varlink(e)
+    }
+}
+
+/// A failure of the service, not of the call.
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Varlink(varlink::server::Error::internal_error(e.to_string()))
+    }
+}
+
+/// Names which are no Rust identifiers as they are
+pub trait Interface {
+    fn get_ip_address(&self, r#match: Match, r#where: Option<String>) -> Result<GetIPAddressReply, Error>;
+    fn r#type(&self, __: i64) -> Result<TypeReply, Error>;
+}
+
+/// Serves an implementation of `Interface`.
+pub struct Server<T>(pub T);
+
+pub fn new<T: Interface + 'static>(inner: T) -> Box<varlink::server::Interface> {
+    Box::new(Server(inner))
+}
+
+impl<T: Interface> varlink::server::Interface for Server<T> {
+    fn get_description(&self) -> &'static str {
+        r#"# Names which are no Rust identifiers as they are
+interface org.example.keywords
+
+type Match (
+  type: string,
+  self: ?int,
+  ref: (move, static, no_op, self)
+)
+
+method GetIPAddress(match: Match, where: ?string) -> (loop: bool, ifIndex: ?int)
+
+method Type(_: int) -> ()
+"#
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.keywords"
+    }
+
+    fn call(&self,
+            req: varlink::server::Request)
+            -> Result<serde_json::Value, varlink::server::Error> {
+        match req.method.as_ref() {
+            "org.example.keywords.GetIPAddress" => {
+                let mut parameters = varlink::server::parameters(req.parameters)?;
+                let args = GetIPAddressArgs {
+                    r#match: varlink::server::parameter(&mut parameters, "match")?,
+                    r#where: varlink::server::parameter(&mut parameters, "where")?,
+                };
+                let reply = self.0.get_ip_address(args.r#match, args.r#where)?;
+                serde_json::to_value(reply).map_err(varlink::server::Error::invalid_reply)
+            }
+            "org.example.keywords.Type" => {
+                let mut parameters = varlink::server::parameters(req.parameters)?;
+                let args = TypeArgs {
+                    __: varlink::server::parameter(&mut parameters, "_")?,
+                };
+                let reply = self.0.r#type(args.__)?;
+                serde_json::to_value(reply).map_err(varlink::server::Error::invalid_reply)
+            }
+            m => Err(varlink::server::Error::method_not_found(m)),
+        }
+    }
+}
+
+/// Calls the methods of the interface on a connection. The replies come
+/// with the connection, for further calls.
+pub struct Client<T>(pub varlink::client::Connection<T>);
+
+impl<T> Client<T>
+    where T: varlink::client::AsyncRead + varlink::client::AsyncWrite + 'static
+{
+    pub fn get_ip_address(self, r#match: Match, r#where: Option<String>)
+        -> varlink::client::TypedCall<T, GetIPAddressReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.keywords.GetIPAddress".into(),
+            ..Default::default()
+        };
+        self.0.call_typed_with(req, &GetIPAddressArgs { r#match, r#where })
+    }
+
+    pub fn get_ip_address_more(self, r#match: Match, r#where: Option<String>)
+        -> varlink::client::TypedReplies<T, GetIPAddressReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.keywords.GetIPAddress".into(),
+            ..Default::default()
+        };
+        self.0.call_more_typed_with(req, &GetIPAddressArgs { r#match, r#where })
+    }
+
+    pub fn r#type(self, __: i64)
+        -> varlink::client::TypedCall<T, TypeReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.keywords.Type".into(),
+            ..Default::default()
+        };
+        self.0.call_typed_with(req, &TypeArgs { __ })
+    }
+
+    pub fn type_more(self, __: i64)
+        -> varlink::client::TypedReplies<T, TypeReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.keywords.Type".into(),
+            ..Default::default()
+        };
+        self.0.call_more_typed_with(req, &TypeArgs { __ })
+    }
+}
@@ -0,0 +1,175 @@
+//! An interface with anonymous structs and enums
+
+use serde_json;
+use varlink;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Netdev {
+    pub ifindex: i64,
+    pub state: NetdevState,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub link: Option<NetdevLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum NetdevState {
+    #[serde(rename = "up")]
+    Up,
+    #[serde(rename = "down")]
+    Down,
+    #[serde(rename = "no_carrier")]
+    NoCarrier,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum NetdevLinkDuplex {
+    #[serde(rename = "half")]
+    Half,
+    #[serde(rename = "full")]
+    Full,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetdevLink {
+    pub speed: i64,
+    pub duplex: NetdevLinkDuplex,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfoReply {
+    pub netdev: Netdev,
+    pub addresses: Vec<InfoReplyAddresses>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InfoReplyAddressesFamily {
+    #[serde(rename = "inet")]
+    Inet,
+    #[serde(rename = "inet6")]
+    Inet6,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfoReplyAddresses {
+    pub family: InfoReplyAddressesFamily,
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfoArgs {
+    pub ifindex: i64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub filter: Option<InfoArgsFilter>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfoArgsFilter {
+    pub up_only: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Any other error, e.g. of `org.varlink.service`.
+    Varlink(varlink::server::Error),
+}
+
+impl From<Error> for varlink::server::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Varlink(e) => e,
+        }
+    }
+}
+
+/// Errors of this interface with parameters which don't match are kept
+/// as `Error::Varlink`.
+impl From<varlink::server::Error> for Error {
+    fn from(e: varlink::server::Error) -> Self {
+        Error::Varlink(e)
+    }
+}
+
+/// A failure of the service, not of the call.
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Varlink(varlink::server::Error::internal_error(e.to_string()))
+    }
+}
+
+/// An interface with anonymous structs and enums
+pub trait Interface {
+    fn info(&self, ifindex: i64, filter: Option<InfoArgsFilter>) -> Result<InfoReply, Error>;
+}
+
+/// Serves an implementation of `Interface`.
+pub struct Server<T>(pub T);
+
+pub fn new<T: Interface + 'static>(inner: T) -> Box<varlink::server::Interface> {
+    Box::new(Server(inner))
+}
+
+impl<T: Interface> varlink::server::Interface for Server<T> {
+    fn get_description(&self) -> &'static str {
+        r#"# An interface with anonymous structs and enums
+interface org.example.nested
+
+type Netdev (
+  ifindex: int,
+  state: (up, down, no_carrier),
+  link: ?(speed: int, duplex: (half, full))
+)
+
+method Info(ifindex: int, filter: ?(up_only: bool)) -> (
+  netdev: Netdev,
+  addresses: (family: (inet, inet6), address: string)[]
+)
+"#
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.nested"
+    }
+
+    fn call(&self,
+            req: varlink::server::Request)
+            -> Result<serde_json::Value, varlink::server::Error> {
+        match req.method.as_ref() {
+            "org.example.nested.Info" => {
+                let mut parameters = varlink::server::parameters(req.parameters)?;
+                let args = InfoArgs {
+                    ifindex: varlink::server::parameter(&mut parameters, "ifindex")?,
+                    filter: varlink::server::parameter(&mut parameters, "filter")?,
+                };
+                let reply = self.0.info(args.ifindex, args.filter)?;
+                serde_json::to_value(reply).map_err(varlink::server::Error::invalid_reply)
+            }
+            m => Err(varlink::server::Error::method_not_found(m)),
+        }
+    }
+}
+
+/// Calls the methods of the interface on a connection. The replies come
+/// with the connection, for further calls.
+pub struct Client<T>(pub varlink::client::Connection<T>);
+
+impl<T> Client<T>
+    where T: varlink::client::AsyncRead + varlink::client::AsyncWrite + 'static
+{
+    pub fn info(self, ifindex: i64, filter: Option<InfoArgsFilter>)
+        -> varlink::client::TypedCall<T, InfoReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.nested.Info".into(),
+            ..Default::default()
+        };
+        self.0.call_typed_with(req, &InfoArgs { ifindex, filter })
+    }
+
+    pub fn info_more(self, ifindex: i64, filter: Option<InfoArgsFilter>)
+        -> varlink::client::TypedReplies<T, InfoReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.nested.Info".into(),
+            ..Default::default()
+        };
+        self.0.call_more_typed_with(req, &InfoArgs { ifindex, filter })
+    }
+}
@@ -0,0 +1,228 @@
+//! An interface to test the generated code
+
+use serde_json;
+use varlink;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Pong {
+    pub ping: String,
+    /// The number of pings so far
+    pub count: i64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingReply {
+    pub pong: Pong,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingArgs {
+    pub ping: String,
+    pub count: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingsReply {
+    pub pongs: Vec<Pong>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingsArgs {
+    pub pings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResetReply {
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The ping was empty
+    InvalidPing(InvalidPingError),
+    /// Any other error, e.g. of `org.varlink.service`.
+    Varlink(varlink::server::Error),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvalidPingError {
+    pub ping: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reason: Option<String>,
+}
+
+impl From<Error> for varlink::server::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::InvalidPing(p) => {
+                match serde_json::to_value(p) {
+                    Ok(p) => varlink::server::Error::new("org.example.ping.InvalidPing", Some(p)),
+                    Err(e) => varlink::server::Error::internal_error(e.to_string()),
+                }
+            }
+            Error::Varlink(e) => e,
+        }
+    }
+}
+
+/// Errors of this interface with parameters which don't match are kept
+/// as `Error::Varlink`.
+impl From<varlink::server::Error> for Error {
+    fn from(e: varlink::server::Error) -> Self {
+        if e.error == "org.example.ping.InvalidPing" {
+            let parameters = e.parameters.clone().unwrap_or_default();
+            if let Ok(p) = serde_json::from_value(parameters) {
+                return Error::InvalidPing(p);
+            }
+        }
+        Error::Varlink(e)
+    }
+}
+
+/// A failure of the service, not of the call.
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Varlink(varlink::server::Error::internal_error(e.to_string()))
+    }
+}
+
+/// An interface to test the generated code
+pub trait Interface {
+    /// Answers a ping
+    fn ping(&self, ping: String, count: i64) -> Result<PingReply, Error>;
+    fn pings(&self, pings: Vec<String>, note: Option<String>) -> Result<PingsReply, Error>;
+    fn reset(&self) -> Result<ResetReply, Error>;
+}
+
+/// Serves an implementation of `Interface`.
+pub struct Server<T>(pub T);
+
+pub fn new<T: Interface + 'static>(inner: T) -> Box<varlink::server::Interface> {
+    Box::new(Server(inner))
+}
+
+impl<T: Interface> varlink::server::Interface for Server<T> {
+    fn get_description(&self) -> &'static str {
+        r#"# An interface to test the generated code
+interface org.example.ping
+
+type Pong (
+  ping: string,
+  # The number of pings so far
+  count: int,
+  note: ?string
+)
+
+# Answers a ping
+method Ping(ping: string, count: int) -> (pong: Pong)
+
+method Pings(pings: string[], note: ?string) -> (pongs: Pong[])
+
+method Reset() -> ()
+
+# The ping was empty
+error InvalidPing (ping: string, reason: ?string)
+"#
+    }
+
+    fn get_name(&self) -> &'static str {
+        "org.example.ping"
+    }
+
+    fn call(&self,
+            req: varlink::server::Request)
+            -> Result<serde_json::Value, varlink::server::Error> {
+        match req.method.as_ref() {
+            "org.example.ping.Ping" => {
+                let mut parameters = varlink::server::parameters(req.parameters)?;
+                let args = PingArgs {
+                    ping: varlink::server::parameter(&mut parameters, "ping")?,
+                    count: varlink::server::parameter(&mut parameters, "count")?,
+                };
+                let reply = self.0.ping(args.ping, args.count)?;
+                serde_json::to_value(reply).map_err(varlink::server::Error::invalid_reply)
+            }
+            "org.example.ping.Pings" => {
+                let mut parameters = varlink::server::parameters(req.parameters)?;
+                let args = PingsArgs {
+                    pings: varlink::server::parameter(&mut parameters, "pings")?,
+                    note: varlink::server::parameter(&mut parameters, "note")?,
+                };
+                let reply = self.0.pings(args.pings, args.note)?;
+                serde_json::to_value(reply).map_err(varlink::server::Error::invalid_reply)
+            }
+            "org.example.ping.Reset" => {
+                let reply = self.0.reset()?;
+                serde_json::to_value(reply).map_err(varlink::server::Error::invalid_reply)
+            }
+            m => Err(varlink::server::Error::method_not_found(m)),
+        }
+    }
+}
+
+/// Calls the methods of the interface on a connection. The replies come
+/// with the connection, for further calls.
+pub struct Client<T>(pub varlink::client::Connection<T>);
+
+impl<T> Client<T>
+    where T: varlink::client::AsyncRead + varlink::client::AsyncWrite + 'static
+{
+    /// Answers a ping
+    pub fn ping(self, ping: String, count: i64)
+        -> varlink::client::TypedCall<T, PingReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.ping.Ping".into(),
+            ..Default::default()
+        };
+        self.0.call_typed_with(req, &PingArgs { ping, count })
+    }
+
+    /// Answers a ping
+    pub fn ping_more(self, ping: String, count: i64)
+        -> varlink::client::TypedReplies<T, PingReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.ping.Ping".into(),
+            ..Default::default()
+        };
+        self.0.call_more_typed_with(req, &PingArgs { ping, count })
+    }
+
+    pub fn pings(self, pings: Vec<String>, note: Option<String>)
+        -> varlink::client::TypedCall<T, PingsReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.ping.Pings".into(),
+            ..Default::default()
+        };
+        self.0.call_typed_with(req, &PingsArgs { pings, note })
+    }
+
+    pub fn pings_more(self, pings: Vec<String>, note: Option<String>)
+        -> varlink::client::TypedReplies<T, PingsReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.ping.Pings".into(),
+            ..Default::default()
+        };
+        self.0.call_more_typed_with(req, &PingsArgs { pings, note })
+    }
+
+    pub fn reset(self)
+        -> varlink::client::TypedCall<T, ResetReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.ping.Reset".into(),
+            ..Default::default()
+        };
+        self.0.call_typed(req)
+    }
+
+    pub fn reset_more(self)
+        -> varlink::client::TypedReplies<T, ResetReply, Error> {
+        let req = varlink::server::Request {
+            method: "org.example.ping.Reset".into(),
+            ..Default::default()
+        };
+        self.0.call_more_typed(req)
+    }
+}
@@ -0,0 +1,17 @@
+use std::env;
+use std::process::Command;
+
+/// Spans tell their line and column since Rust 1.88, see `file_position`.
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(span_locations)");
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let minor = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .and_then(|version| version.split('.').nth(1).and_then(|m| m.parse::<u32>().ok()));
+    if minor >= Some(88) {
+        println!("cargo:rustc-cfg=span_locations");
+    }
+}
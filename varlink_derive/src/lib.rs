@@ -0,0 +1,255 @@
+//! Generates the code for a varlink interface from its description at compile
+//! time, like `varlink::generator` does in `build.rs`:
+//!
+//! ```rust
+//! extern crate serde_json;
+//! #[macro_use]
+//! extern crate serde_derive;
+//! extern crate varlink;
+//! #[macro_use]
+//! extern crate varlink_derive;
+//!
+//! mod org_example_ping {
+//!     varlink_server!(r#"
+//! interface org.example.ping
+//! method Ping(ping: string) -> (pong: string)
+//! "#);
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! An invalid description is a compile error at the string, which tells the
+//! line and column of the error in the file:
+//!
+//! ```rust,compile_fail
+//! extern crate serde_json;
+//! #[macro_use]
+//! extern crate serde_derive;
+//! extern crate varlink;
+//! #[macro_use]
+//! extern crate varlink_derive;
+//!
+//! mod org_example_ping {
+//!     varlink_server!(r#"
+//! interface org.example.ping
+//! method Ping(ping) -> (pong: string)
+//! "#);
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! The line and column in the file need Rust 1.88, older compilers only tell
+//! the position in the description.
+//!
+//! `varlink_server_file!` reads the description from a file instead, with the
+//! path relative to the directory of the crate's `Cargo.toml`:
+//!
+//! ```rust,ignore
+//! mod org_example_ping {
+//!     varlink_server_file!("src/org.example.ping.varlink");
+//! }
+//! ```
+
+extern crate proc_macro;
+extern crate varlink;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use varlink::{generator, parser};
+
+#[proc_macro]
+pub fn varlink_server(input: TokenStream) -> TokenStream {
+    let (lit, description) = match string_argument(input, "the interface description") {
+        Ok(arg) => arg,
+        Err(e) => return e,
+    };
+    expand(&description,
+           lit.span(),
+           |line, column| {
+               file_position(&lit, line, column)
+                   .map(|(line, column)| format!("at line {}, column {}", line, column))
+           })
+}
+
+#[proc_macro]
+pub fn varlink_server_file(input: TokenStream) -> TokenStream {
+    let (lit, path) = match string_argument(input, "the path of the interface description") {
+        Ok(arg) => arg,
+        Err(e) => return e,
+    };
+    let path = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default()).join(path);
+    let mut description = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut description)) {
+        return compile_error(&format!("could not read {}: {}", path.display(), e), lit.span());
+    }
+    let code = expand(&description,
+                      lit.span(),
+                      |line, column| Some(format!("in {}:{}:{}", path.display(), line, column)));
+    // Builds again when the file changes.
+    let mut depend: TokenStream = format!("const _: &str = include_str!({:?});",
+                                          path.display().to_string())
+            .parse()
+            .unwrap();
+    depend.extend(code);
+    depend
+}
+
+/// The single string literal of `input` and its value, or a compile error
+/// expecting `what`.
+fn string_argument(input: TokenStream, what: &str) -> Result<(Literal, String), TokenStream> {
+    let msg = format!("expected {} as a string", what);
+    let mut tokens = input.into_iter();
+    let lit = match (tokens.next(), tokens.next()) {
+        (Some(TokenTree::Literal(lit)), None) => lit,
+        (Some(tt), _) => return Err(compile_error(&msg, tt.span())),
+        (None, _) => return Err(compile_error(&msg, Span::call_site())),
+    };
+    match unquote(&lit.to_string()) {
+        Some(s) => Ok((lit, s)),
+        None => Err(compile_error(&msg, lit.span())),
+    }
+}
+
+/// The code for `description`, or a compile error at `span`. `position`
+/// describes where `line` and `column` of the description are in the source,
+/// if it knows.
+fn expand<F>(description: &str, span: Span, position: F) -> TokenStream
+    where F: Fn(usize, usize) -> Option<String>
+{
+    let code = match generator::compile(description) {
+        Ok(code) => code,
+        Err(e) => {
+            let position = parser::error_position(description)
+                .and_then(|(line, column)| position(line, column));
+            let msg = match position {
+                Some(position) => format!("invalid interface description {}: {}", position, e),
+                None => format!("invalid interface description: {}", e),
+            };
+            return compile_error(&msg, span);
+        }
+    };
+    match code.parse() {
+        Ok(ts) => ts,
+        Err(_) => compile_error("the generated code is invalid", span),
+    }
+}
+
+/// The position in the source file of `line` and `column` of the value of
+/// `lit`, all starting at 1. Escape sequences before the position are not
+/// accounted for, raw strings don't have any.
+#[cfg(span_locations)]
+fn file_position(lit: &Literal, line: usize, column: usize) -> Option<(usize, usize)> {
+    let start = lit.span().start();
+    if line > 1 {
+        return Some((start.line() + line - 1, column));
+    }
+    Some((start.line(), start.column() + quote_len(&lit.to_string()) + column - 1))
+}
+
+/// Spans have no line and column before Rust 1.88.
+#[cfg(not(span_locations))]
+fn file_position(_: &Literal, _: usize, _: usize) -> Option<(usize, usize)> {
+    None
+}
+
+/// The length of the opening quote of a string literal, e.g. 3 for `r#"`.
+#[cfg(span_locations)]
+fn quote_len(lit: &str) -> usize {
+    lit.find('"').map_or(0, |n| n + 1)
+}
+
+/// `compile_error!("msg")`, reported at `span`.
+fn compile_error(msg: &str, span: Span) -> TokenStream {
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut msg = Literal::string(msg);
+    msg.set_span(span);
+    let mut args = Group::new(Delimiter::Parenthesis, TokenTree::Literal(msg).into());
+    args.set_span(span);
+    let mut semi = Punct::new(';', Spacing::Alone);
+    semi.set_span(span);
+    vec![TokenTree::Ident(Ident::new("compile_error", span)),
+         TokenTree::Punct(bang),
+         TokenTree::Group(args),
+         TokenTree::Punct(semi)]
+            .into_iter()
+            .collect()
+}
+
+/// The value of a string literal from its source, e.g. `r#"a"#` or `"a\n"`.
+fn unquote(lit: &str) -> Option<String> {
+    if let Some(raw) = lit.strip_prefix('r') {
+        let hashes = raw.chars().take_while(|&c| c == '#').count();
+        let quotes = hashes + 1;
+        if raw.len() < 2 * quotes || !raw[hashes..].starts_with('"') {
+            return None;
+        }
+        return Some(raw[quotes..raw.len() - quotes].to_string());
+    }
+    if lit.len() < 2 || !lit.starts_with('"') || !lit.ends_with('"') {
+        return None;
+    }
+
+    let mut s = String::new();
+    let mut chars = lit[1..lit.len() - 1].chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => s.push('\n'),
+            'r' => s.push('\r'),
+            't' => s.push('\t'),
+            '0' => s.push('\0'),
+            '\\' => s.push('\\'),
+            '\'' => s.push('\''),
+            '"' => s.push('"'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                s.push(u8::from_str_radix(&hex, 16).ok()? as char);
+            }
+            'u' => {
+                if chars.next()? != '{' {
+                    return None;
+                }
+                let hex: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                s.push(::std::char::from_u32(u32::from_str_radix(&hex.replace('_', ""), 16).ok()?)?);
+            }
+            // A line continuation skips the newline and the leading whitespace.
+            '\n' => {
+                while let Some(&c) = chars.peek() {
+                    if !c.is_whitespace() {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(s)
+}
+
+#[test]
+fn test_unquote() {
+    assert_eq!(unquote(r#""a\"b\\c\n\x41\u{1F600}""#), Some("a\"b\\c\nA\u{1F600}".into()));
+    assert_eq!(unquote("\"a\\\n    b\""), Some("ab".into()));
+    assert_eq!(unquote(r###"r#"a"b"#"###), Some("a\"b".into()));
+    assert_eq!(unquote(r#"r"a""#), Some("a".into()));
+    assert_eq!(unquote("b\"a\""), None);
+    assert_eq!(unquote("1"), None);
+    assert_eq!(unquote(r#""\q""#), None);
+}
+
+#[cfg(span_locations)]
+#[test]
+fn test_quote_len() {
+    assert_eq!(quote_len(r#""a""#), 1);
+    assert_eq!(quote_len(r##"r#"a"#"##), 3);
+}
@@ -0,0 +1,93 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate varlink;
+#[macro_use]
+extern crate varlink_derive;
+
+mod org_example_ping {
+    varlink_server!(r#"
+# Answers pings
+interface org.example.ping
+
+method Ping(ping: string) -> (pong: string)
+
+error InvalidPing (ping: string)
+"#);
+}
+
+mod org_example_file {
+    varlink_server_file!("tests/org.example.file.varlink");
+}
+
+use org_example_ping::*;
+use varlink::server::{assert_conformance, Request, Response, VarlinkService};
+
+struct Pinger;
+
+impl Interface for Pinger {
+    fn ping(&self, ping: String) -> Result<PingReply, Error> {
+        if ping.is_empty() {
+            return Err(Error::InvalidPing(InvalidPingError { ping: ping }));
+        }
+        Ok(PingReply { pong: ping })
+    }
+}
+
+fn call(parameters: serde_json::Value) -> Response {
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![org_example_ping::new(Pinger)]);
+    let req = Request {
+        method: "org.example.ping.Ping".into(),
+        parameters: Some(parameters),
+        ..Default::default()
+    };
+    assert_conformance(&service, req).pop().unwrap()
+}
+
+#[test]
+fn test_varlink_server() {
+    match call(json!({"ping": "a"})) {
+        Response::Ok(reply) => assert_eq!(reply.parameters, Some(json!({"pong": "a"}))),
+        _ => panic!("expected a reply"),
+    }
+    match call(json!({"ping": ""})) {
+        Response::Err(e) => {
+            assert_eq!(e.error, "org.example.ping.InvalidPing");
+            assert_eq!(e.parameters, Some(json!({"ping": ""})));
+        }
+        _ => panic!("expected an error"),
+    }
+}
+
+struct Toucher;
+
+impl org_example_file::Interface for Toucher {
+    fn touch(&self) -> Result<org_example_file::TouchReply, org_example_file::Error> {
+        Ok(org_example_file::TouchReply {})
+    }
+}
+
+#[test]
+fn test_varlink_server_file() {
+    let iface = org_example_file::new(Toucher);
+    assert_eq!(iface.get_description(), include_str!("org.example.file.varlink"));
+    let service = VarlinkService::new("org.varlink".into(),
+                                      "test".into(),
+                                      "0.1".into(),
+                                      "http://varlink.org".into(),
+                                      vec![iface]);
+    let req = Request {
+        method: "org.example.file.Touch".into(),
+        ..Default::default()
+    };
+    match assert_conformance(&service, req).pop().unwrap() {
+        Response::Ok(_) => {}
+        _ => panic!("expected a reply"),
+    }
+}